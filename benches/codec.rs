@@ -0,0 +1,166 @@
+// [[file:../ipi.note::9d1e4b73][9d1e4b73]]
+//! Throughput of i-PI codecs for POSDATA and FORCEREADY messages of large
+//! periodic systems. The coordinates are also encoded and decoded one f64
+//! at a time as in the former codec, reported as baseline for the bulk
+//! decoding and encoding.
+//!
+//! Run with:
+//!
+//!     cargo bench --features adhoc --bench codec
+
+use bytes::{Buf, BufMut, BytesMut};
+use gosh_core::gchemol::prelude::*;
+use gosh_core::gchemol::{Atom, Lattice, Molecule};
+use gosh_ipi::docs::codec::{ClientCodec, ServerCodec};
+use gosh_ipi::{ClientMessage, ServerMessage};
+use tokio_util::codec::{Decoder, Encoder};
+
+use std::time::{Duration, Instant};
+// 9d1e4b73 ends here
+
+// [[file:../ipi.note::4be07f1c][4be07f1c]]
+/// Return the average time of calling `f` repeatedly for about one second.
+fn measure(mut f: impl FnMut()) -> Duration {
+    // warm up
+    f();
+    let start = Instant::now();
+    let mut n = 0;
+    while n < 3 || start.elapsed() < Duration::from_secs(1) {
+        f();
+        n += 1;
+    }
+    start.elapsed() / n
+}
+
+/// A simple cubic crystal of carbon atoms
+fn crystal(natoms: usize) -> Molecule {
+    let n = (natoms as f64).cbrt().ceil() as usize;
+    let d = 1.5;
+    let atoms = (0..natoms).map(|i| {
+        let (x, y, z) = (i % n, i / n % n, i / (n * n));
+        Atom::new("C", [x as f64 * d, y as f64 * d, z as f64 * d])
+    });
+    let mut mol = Molecule::from_atoms(atoms);
+    let a = n as f64 * d;
+    mol.set_lattice(Lattice::new([[a, 0.0, 0.0], [0.0, a, 0.0], [0.0, 0.0, a]]));
+    mol
+}
+
+/// FORCEREADY message sent by driver for `natoms`
+fn forceready(natoms: usize) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(12 + 8 + 4 + 24 * natoms + 72 + 4);
+    buf.put_slice(b"FORCEREADY  ");
+    buf.put_f64_le(-1.0);
+    buf.put_u32_le(natoms as u32);
+    for i in 0..3 * natoms {
+        buf.put_f64_le(i as f64 * 1e-6);
+    }
+    for _ in 0..9 {
+        buf.put_f64_le(0.0);
+    }
+    buf.put_u32_le(0);
+    buf
+}
+
+/// Baseline: decode coordinates in `frame` one f64 at a time, skipping
+/// `offset` bytes before them.
+fn decode_coords_baseline(frame: &[u8], offset: usize, natoms: usize) -> Vec<[f64; 3]> {
+    let mut src = &frame[offset..];
+    let mut coords = vec![[0.0; 3]; natoms];
+    for i in 0..natoms {
+        for j in 0..3 {
+            coords[i][j] = src.get_f64_le() * 0.5;
+        }
+    }
+    coords
+}
+
+/// Baseline: encode coordinates one f64 at a time.
+fn encode_coords_baseline(buf: &mut BytesMut, coords: &[[f64; 3]]) {
+    for [x, y, z] in coords {
+        buf.put_f64_le(x * 2.0);
+        buf.put_f64_le(y * 2.0);
+        buf.put_f64_le(z * 2.0);
+    }
+}
+
+fn report(what: &str, natoms: usize, nbytes: usize, t: Duration) {
+    let secs = t.as_secs_f64();
+    println!(
+        "{what:<18} {natoms:>9} {:>12.3} {:>10.1} {:>12.3e}",
+        secs * 1e3,
+        nbytes as f64 / secs / 1e6,
+        natoms as f64 / secs
+    );
+}
+
+fn main() {
+    println!("{:<18} {:>9} {:>12} {:>10} {:>12}", "message", "natoms", "time/ms", "MB/s", "atoms/s");
+    for natoms in [1_000, 10_000, 100_000, 1_000_000] {
+        // POSDATA encoded by server: the molecule is consumed by encoder, so
+        // the time for cloning is excluded
+        let mol = crystal(natoms);
+        let t_clone = measure(|| drop(mol.clone()));
+        let mut buf = BytesMut::new();
+        let t = measure(|| {
+            buf.clear();
+            ServerCodec::default().encode(ServerMessage::PosData(mol.clone()), &mut buf).unwrap();
+        });
+        report("encode POSDATA", natoms, buf.len(), t.saturating_sub(t_clone));
+        let positions: Vec<_> = mol.positions().collect();
+        let mut base = BytesMut::with_capacity(24 * natoms);
+        let t = measure(|| {
+            base.clear();
+            encode_coords_baseline(&mut base, &positions);
+        });
+        report("  per-f64 coords", natoms, base.len(), t);
+
+        // POSDATA decoded by driver, including building the molecule. The
+        // frame is cloned for each decoding, so the time for cloning is
+        // excluded. The codec is reused as in a session.
+        let frame = buf.clone();
+        let t_clone = measure(|| drop(frame.clone()));
+        let mut codec = ServerCodec::default();
+        let t = measure(|| {
+            let mut src = frame.clone();
+            let msg = codec.decode(&mut src).unwrap();
+            assert!(matches!(msg, Some(ServerMessage::PosData(_))));
+        });
+        report("decode POSDATA", natoms, frame.len(), t.saturating_sub(t_clone));
+        let t = measure(|| drop(decode_coords_baseline(&frame, 12 + 144 + 4, natoms)));
+        report("  per-f64 coords", natoms, frame.len(), t);
+
+        // FORCEREADY decoded by server
+        let frame = forceready(natoms);
+        let t_clone = measure(|| drop(frame.clone()));
+        let mut codec = ClientCodec::default();
+        let mut computed = None;
+        let t = measure(|| {
+            let mut src = frame.clone();
+            match codec.decode(&mut src).unwrap() {
+                Some(ClientMessage::ForceReady(c)) => computed = Some(c),
+                _ => panic!("invalid FORCEREADY"),
+            }
+        });
+        report("decode FORCEREADY", natoms, frame.len(), t.saturating_sub(t_clone));
+        let t = measure(|| drop(decode_coords_baseline(&frame, 12 + 8 + 4, natoms)));
+        report("  per-f64 coords", natoms, frame.len(), t);
+
+        // FORCEREADY encoded by driver
+        let computed = computed.unwrap();
+        let t_clone = measure(|| drop(computed.clone()));
+        let mut buf = BytesMut::new();
+        let t = measure(|| {
+            buf.clear();
+            ClientCodec::default().encode(ClientMessage::ForceReady(computed.clone()), &mut buf).unwrap();
+        });
+        report("encode FORCEREADY", natoms, buf.len(), t.saturating_sub(t_clone));
+        let mut base = BytesMut::with_capacity(24 * natoms);
+        let t = measure(|| {
+            base.clear();
+            encode_coords_baseline(&mut base, &computed.forces);
+        });
+        report("  per-f64 coords", natoms, base.len(), t);
+    }
+}
+// 4be07f1c ends here
@@ -0,0 +1,305 @@
+// [[file:../ipi.note::2ff5359a][2ff5359a]]
+use super::*;
+
+use serde::{Deserialize, Serialize};
+// 2ff5359a ends here
+
+// [[file:../ipi.note::202e8459][202e8459]]
+/// A user-defined restraint on atoms. Atom numbers are 1-based as in
+/// gchemol. Distances are in Å, angles in degrees, and force constants in
+/// eV/Å^2 or eV/rad^2.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Restraint {
+    /// Harmonic restraint on the distance between two atoms
+    Distance { atoms: [usize; 2], k: f64, r0: f64 },
+    /// Harmonic restraint on the angle formed by three atoms
+    Angle { atoms: [usize; 3], k: f64, theta0: f64 },
+    /// Harmonic restraint on the dihedral angle formed by four atoms
+    Dihedral { atoms: [usize; 4], k: f64, phi0: f64 },
+    /// Flat-bottom wall on the distance between two atoms, which is only
+    /// active when the distance is out of range `lower` ~ `upper`.
+    Wall {
+        atoms: [usize; 2],
+        k: f64,
+        lower: Option<f64>,
+        upper: Option<f64>,
+    },
+    /// Harmonic restraint on the position of one atom
+    Position { atom: usize, k: f64, position: [f64; 3] },
+}
+
+/// External bias potential consisting of restraints, which will be added on
+/// top of computed energy and forces.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BiasPotential {
+    restraints: Vec<Restraint>,
+}
+// 202e8459 ends here
+
+// [[file:../ipi.note::6fb88ee1][6fb88ee1]]
+type Vector3 = [f64; 3];
+
+fn sub(a: Vector3, b: Vector3) -> Vector3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn add(a: Vector3, b: Vector3) -> Vector3 {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn dot(a: Vector3, b: Vector3) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: Vector3, b: Vector3) -> Vector3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn scale(s: f64, a: Vector3) -> Vector3 {
+    [s * a[0], s * a[1], s * a[2]]
+}
+
+fn norm(a: Vector3) -> f64 {
+    dot(a, a).sqrt()
+}
+
+/// Lengths below this are treated as zero, for which gradients are
+/// undefined.
+const EPSILON: f64 = 1e-8;
+
+/// Return the distance between `p1` and `p2`, and its gradients.
+fn distance_with_gradient(p1: Vector3, p2: Vector3) -> Result<(f64, [Vector3; 2])> {
+    let d = sub(p1, p2);
+    let r = norm(d);
+    ensure!(r > EPSILON, "distance is zero in restraint");
+    let g = scale(1.0 / r, d);
+    Ok((r, [g, scale(-1.0, g)]))
+}
+
+/// Return the angle (in radian) formed by `p1`-`p2`-`p3`, and its gradients.
+fn angle_with_gradient(p1: Vector3, p2: Vector3, p3: Vector3) -> Result<(f64, [Vector3; 3])> {
+    let u = sub(p1, p2);
+    let v = sub(p3, p2);
+    let (nu, nv) = (norm(u), norm(v));
+    ensure!(nu > EPSILON && nv > EPSILON, "bond length is zero in angle restraint");
+    let c = (dot(u, v) / (nu * nv)).clamp(-1.0, 1.0);
+    let theta = c.acos();
+    // avoid dividing by zero for linear angles
+    let s = theta.sin().max(1e-8);
+    let g1 = scale(-1.0 / s, sub(scale(1.0 / (nu * nv), v), scale(c / (nu * nu), u)));
+    let g3 = scale(-1.0 / s, sub(scale(1.0 / (nu * nv), u), scale(c / (nv * nv), v)));
+    let g2 = scale(-1.0, [g1[0] + g3[0], g1[1] + g3[1], g1[2] + g3[2]]);
+    Ok((theta, [g1, g2, g3]))
+}
+
+/// Return the dihedral angle (in radian) formed by `p1`-`p2`-`p3`-`p4`, and
+/// its gradients.
+fn dihedral_with_gradient(p1: Vector3, p2: Vector3, p3: Vector3, p4: Vector3) -> Result<(f64, [Vector3; 4])> {
+    // dihedral angle
+    let b1 = sub(p2, p1);
+    let b2 = sub(p3, p2);
+    let b3 = sub(p4, p3);
+    let m = cross(b1, b2);
+    let n = cross(b2, b3);
+    let phi = (dot(cross(m, n), b2) / norm(b2)).atan2(dot(m, n));
+
+    // gradients following Bekker's formulation
+    let f = sub(p1, p2);
+    let g = sub(p2, p3);
+    let h = sub(p4, p3);
+    let a = cross(f, g);
+    let b = cross(h, g);
+    let (aa, bb, ng) = (dot(a, a), dot(b, b), norm(g));
+    ensure!(
+        aa > EPSILON * EPSILON && bb > EPSILON * EPSILON,
+        "dihedral is undefined for collinear atoms in restraint"
+    );
+    let fg = dot(f, g) / (aa * ng);
+    let hg = dot(h, g) / (bb * ng);
+    let g1 = scale(-ng / aa, a);
+    let g4 = scale(ng / bb, b);
+    let g2: Vector3 = std::array::from_fn(|i| ng / aa * a[i] + fg * a[i] - hg * b[i]);
+    let g3: Vector3 = std::array::from_fn(|i| -ng / bb * b[i] - fg * a[i] + hg * b[i]);
+    Ok((phi, [g1, g2, g3, g4]))
+}
+// 6fb88ee1 ends here
+
+// [[file:../ipi.note::46162774][46162774]]
+impl Restraint {
+    /// Add the bias energy and forces of this restraint for atoms in
+    /// `positions` into `energy` and `forces`. Displacements between atoms
+    /// are in minimum image if periodic `cell` is given.
+    fn accumulate(
+        &self,
+        positions: &[Vector3],
+        cell: Option<&[[f64; 3]; 3]>,
+        energy: &mut f64,
+        forces: &mut [Vector3],
+    ) -> Result<()> {
+        let get = |i: usize| {
+            ensure!(i >= 1 && i <= positions.len(), "invalid atom number in restraint: {i}");
+            Ok(positions[i - 1])
+        };
+        // atoms in restraint unwrapped around the first one
+        let get_all = |atoms: &[usize]| -> Result<Vec<Vector3>> {
+            let origin = get(atoms[0])?;
+            atoms
+                .iter()
+                .map(|&i| Ok(add(origin, minimum_image(cell, sub(get(i)?, origin)))))
+                .collect()
+        };
+        let mut add_forces = |atoms: &[usize], de: f64, grads: &[Vector3]| {
+            for (&i, g) in atoms.iter().zip(grads) {
+                for k in 0..3 {
+                    forces[i - 1][k] -= de * g[k];
+                }
+            }
+        };
+
+        match self {
+            Self::Distance { atoms, k, r0 } => {
+                let ps = get_all(atoms)?;
+                let (r, grads) = distance_with_gradient(ps[0], ps[1])?;
+                let dr = r - r0;
+                *energy += 0.5 * k * dr * dr;
+                add_forces(atoms, k * dr, &grads);
+            }
+            Self::Angle { atoms, k, theta0 } => {
+                let ps = get_all(atoms)?;
+                let (theta, grads) = angle_with_gradient(ps[0], ps[1], ps[2])?;
+                let dt = theta - theta0.to_radians();
+                *energy += 0.5 * k * dt * dt;
+                add_forces(atoms, k * dt, &grads);
+            }
+            Self::Dihedral { atoms, k, phi0 } => {
+                let ps = get_all(atoms)?;
+                let (phi, grads) = dihedral_with_gradient(ps[0], ps[1], ps[2], ps[3])?;
+                // wrap into [-pi, pi]
+                let dp = phi - phi0.to_radians();
+                let dp = dp.sin().atan2(dp.cos());
+                *energy += 0.5 * k * dp * dp;
+                add_forces(atoms, k * dp, &grads);
+            }
+            Self::Wall { atoms, k, lower, upper } => {
+                let ps = get_all(atoms)?;
+                let (r, grads) = distance_with_gradient(ps[0], ps[1])?;
+                let dr = match (lower, upper) {
+                    (Some(l), _) if r < *l => r - l,
+                    (_, Some(u)) if r > *u => r - u,
+                    _ => 0.0,
+                };
+                *energy += 0.5 * k * dr * dr;
+                add_forces(atoms, k * dr, &grads);
+            }
+            Self::Position { atom, k, position } => {
+                let d = minimum_image(cell, sub(get(*atom)?, *position));
+                *energy += 0.5 * k * dot(d, d);
+                add_forces(&[*atom], *k, &[d]);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl BiasPotential {
+    /// Read restraint definitions from a JSON file like:
+    ///
+    /// ```json
+    /// {"restraints": [{"type": "distance", "atoms": [1, 2], "k": 10.0, "r0": 1.5}]}
+    /// ```
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let s = gut::fs::read_file(path)?;
+        let bias = serde_json::from_str(&s).with_context(|| format!("invalid bias config: {path:?}"))?;
+        Ok(bias)
+    }
+
+    /// Evaluate bias energy and forces for atoms in `positions`, in minimum
+    /// image of periodic `cell` (vectors in rows) if any.
+    pub fn evaluate(&self, positions: &[[f64; 3]], cell: Option<[[f64; 3]; 3]>) -> Result<(f64, Vec<[f64; 3]>)> {
+        let mut energy = 0.0;
+        let mut forces = vec![[0.0; 3]; positions.len()];
+        for r in self.restraints.iter() {
+            r.accumulate(positions, cell.as_ref(), &mut energy, &mut forces)?;
+        }
+        Ok((energy, forces))
+    }
+
+    /// Add bias energy and forces on `computed` for `mol`. The bias energy is
+    /// reported separately as `bias_energy` in the extra data.
+    pub fn apply(&self, mol: &Molecule, computed: &mut Computed) -> Result<()> {
+        let positions: Vec<_> = mol.positions().collect();
+        ensure!(positions.len() == computed.forces.len(), "inconsistent number of atoms");
+        let (energy, forces) = self.evaluate(&positions, cell_vectors(mol))?;
+        computed.energy += energy;
+        for (f, fb) in computed.forces.iter_mut().zip(forces) {
+            for k in 0..3 {
+                f[k] += fb[k];
+            }
+        }
+        computed.insert_extra("bias_energy", energy.into());
+        Ok(())
+    }
+}
+
+impl bridge::Transit for BiasPotential {
+    fn transit(&mut self, mol: &Molecule, computed: &mut Computed) -> Result<()> {
+        self.apply(mol, computed)
+    }
+}
+
+#[test]
+fn test_bias_forces() {
+    use approx::*;
+
+    let positions = [[0.1, 0.0, 0.2], [1.2, 0.1, 0.0], [1.7, 1.3, 0.1], [2.9, 1.5, 0.9]];
+    let bias: BiasPotential = serde_json::from_str(
+        r#"{"restraints": [
+            {"type": "distance", "atoms": [1, 2], "k": 10.0, "r0": 1.5},
+            {"type": "angle", "atoms": [1, 2, 3], "k": 2.0, "theta0": 120.0},
+            {"type": "dihedral", "atoms": [1, 2, 3, 4], "k": 1.0, "phi0": 60.0},
+            {"type": "wall", "atoms": [1, 4], "k": 5.0, "upper": 2.0},
+            {"type": "position", "atom": 3, "k": 3.0, "position": [1.5, 1.0, 0.0]}
+        ]}"#,
+    )
+    .unwrap();
+
+    // compare analytical forces with finite difference
+    let (_, forces) = bias.evaluate(&positions, None).unwrap();
+    let h = 1e-5;
+    for i in 0..positions.len() {
+        for k in 0..3 {
+            let mut p = positions;
+            p[i][k] += h;
+            let (ep, _) = bias.evaluate(&p, None).unwrap();
+            p[i][k] -= 2.0 * h;
+            let (em, _) = bias.evaluate(&p, None).unwrap();
+            assert_relative_eq!(forces[i][k], -(ep - em) / (2.0 * h), epsilon = 1e-5);
+        }
+    }
+
+    // the same atoms across cell boundary give the same bias
+    let cell = [[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [0.0, 0.0, 10.0]];
+    let (e0, f0) = bias.evaluate(&positions, Some(cell)).unwrap();
+    let mut shifted = positions;
+    shifted[0][0] += 10.0;
+    shifted[3][1] -= 10.0;
+    let (e1, f1) = bias.evaluate(&shifted, Some(cell)).unwrap();
+    assert_relative_eq!(e0, e1, epsilon = 1e-8);
+    for (a, b) in f0.iter().zip(&f1) {
+        for k in 0..3 {
+            assert_relative_eq!(a[k], b[k], epsilon = 1e-8);
+        }
+    }
+
+    // overlapping atoms are reported instead of giving NaN forces
+    let mut p = positions;
+    p[1] = p[0];
+    assert!(bias.evaluate(&p, None).is_err());
+}
+// 46162774 ends here
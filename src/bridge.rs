@@ -0,0 +1,488 @@
+// [[file:../ipi.note::391b3ea9][391b3ea9]]
+use super::*;
+use socket::IpiStream;
+
+use futures::SinkExt;
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{FramedRead, FramedWrite};
+// 391b3ea9 ends here
+
+// [[file:../ipi.note::8a2490a7][8a2490a7]]
+/// The communication between i-PI server and the driver, viewed from the
+/// driver side.
+struct IpiDriverStream<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    read: FramedRead<R, codec::ServerCodec>,
+    write: FramedWrite<W, codec::ClientCodec>,
+}
+
+impl<R, W> IpiDriverStream<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    fn new(read: R, write: W, opts: codec::CodecOptions) -> Self {
+        // the message we received from the i-PI server
+        let read = FramedRead::new(read, codec::ServerCodec::new(opts));
+        // the message we sent to the i-PI server
+        let write = FramedWrite::new(write, codec::ClientCodec::new(opts));
+
+        Self { read, write }
+    }
+
+    /// Receive next message from i-PI server. Return None if server closed
+    /// the connection.
+    async fn recv(&mut self) -> Result<Option<ServerMessage>> {
+        match self.read.next().await {
+            Some(msg) => Ok(Some(msg?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn send(&mut self, msg: ClientMessage) -> Result<()> {
+        self.write.send(msg).await?;
+        Ok(())
+    }
+}
+// 8a2490a7 ends here
+
+// [[file:../ipi.note::99564b27][99564b27]]
+/// Inspect or modify computed results in transit, before they are sent back
+/// to the upstream i-PI server.
+pub trait Transit: Send {
+    /// Called for each computed structure `mol`.
+    fn transit(&mut self, mol: &Molecule, computed: &mut Computed) -> Result<()>;
+}
+
+/// Log energy and max force of each structure in transit.
+pub struct TransitLog {
+    file: std::fs::File,
+    istep: usize,
+}
+
+impl TransitLog {
+    /// Create a new log file in `path`.
+    pub fn create(path: &Path) -> Result<Self> {
+        use std::io::Write;
+
+        let mut file = std::fs::File::create(path).with_context(|| format!("create log file {path:?}"))?;
+        writeln!(file, "# {:>6} {:>18} {:>12}", "step", "energy/eV", "fmax/eV/Å")?;
+        Ok(Self { file, istep: 0 })
+    }
+}
+
+impl Transit for TransitLog {
+    fn transit(&mut self, _mol: &Molecule, computed: &mut Computed) -> Result<()> {
+        use std::io::Write;
+
+        let fmax = computed
+            .forces()
+            .iter()
+            .map(|f| f.iter().map(|x| x * x).sum::<f64>().sqrt())
+            .fold(0.0, f64::max);
+        writeln!(self.file, "  {:>6} {:>18.8} {:>12.6}", self.istep, computed.energy(), fmax)?;
+        self.istep += 1;
+        Ok(())
+    }
+}
+// 99564b27 ends here
+
+// [[file:../ipi.note::6fc81436][6fc81436]]
+/// Merge positions and cell of `mol` decoded from POSDATA into `template`,
+/// returning a molecule with all metadata of `template` intact.
+///
+/// POSDATA carries positions and cell only, so all atoms in decoded `mol`
+/// are carbon atoms without labels, masses, charges or freezing flags, and
+/// the title is lost. The atoms in `template` should be in the same order
+/// as sent by i-PI server.
+pub fn merge_positions(template: &Molecule, mol: &Molecule) -> Result<Molecule> {
+    ensure!(
+        template.natoms() == mol.natoms(),
+        "template has {} atoms, but received {} atoms",
+        template.natoms(),
+        mol.natoms()
+    );
+    let mut merged = template.clone();
+    merged.set_positions(mol.positions());
+    match mol.get_lattice() {
+        Some(lat) => merged.set_lattice(*lat),
+        None => merged.unbuild_crystal(),
+    }
+    Ok(merged)
+}
+
+#[test]
+fn test_merge_positions() -> Result<()> {
+    use bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    let mut template = Molecule::from_file("tests/files/quinone.cif")?;
+    template.set_title("quinone slab");
+    let atom = template.get_atom_mut(1).unwrap();
+    atom.set_label("O1");
+    atom.set_freezing([true; 3]);
+
+    // positions moved by i-PI server
+    let mut mol = template.clone();
+    let positions: Vec<_> = mol.positions().map(|[x, y, z]| [x + 0.1, y, z]).collect();
+    mol.set_positions(positions.clone());
+    let mut buf = BytesMut::new();
+    codec::ServerCodec::default().encode(ServerMessage::PosData(mol), &mut buf)?;
+    let decoded = match codec::ServerCodec::default().decode(&mut buf)? {
+        Some(ServerMessage::PosData(mol)) => mol,
+        _ => bail!("invalid POSDATA"),
+    };
+
+    let merged = merge_positions(&template, &decoded)?;
+    assert_eq!(merged.title(), "quinone slab");
+    assert!(merged.symbols().eq(template.symbols()));
+    let atom = merged.get_atom(1).unwrap();
+    assert_eq!(atom.label(), "O1");
+    assert_eq!(atom.freezing(), [true; 3]);
+    for (p1, p2) in merged.positions().zip(&positions) {
+        for k in 0..3 {
+            approx::assert_relative_eq!(p1[k], p2[k], epsilon = 1e-8);
+        }
+    }
+    assert!(merge_positions(&template, &Molecule::from_atoms(vec![Atom::new("C", [0.0; 3])])).is_err());
+
+    Ok(())
+}
+
+/// A bridge acting as a driver towards an upstream i-PI server (e.g. i-PI in
+/// Python doing PIMD), while forwarding each POSDATA to a downstream external
+/// code connected to our own i-PI server.
+///
+/// Positions and forces are converted from/to atomic units by the codecs on
+/// both ends, so [`Transit`] always sees Å and eV.
+pub struct Bridge {
+    template: Option<Molecule>,
+    transits: Vec<Box<dyn Transit>>,
+    /// The binary layout of messages from/to upstream
+    codec: codec::CodecOptions,
+}
+
+impl Bridge {
+    /// Create a bridge. Positions received from upstream will be merged into
+    /// `template` molecule if provided, so that downstream and transits see
+    /// the species, masses, labels and freezing flags of `template`.
+    pub fn new(template: Option<Molecule>) -> Self {
+        Self {
+            template,
+            transits: vec![],
+            codec: codec::CodecOptions::default(),
+        }
+    }
+
+    /// Exchange messages with upstream i-PI server in binary layout
+    /// specified in `codec`.
+    pub fn set_codec(&mut self, codec: codec::CodecOptions) {
+        self.codec = codec;
+    }
+
+    /// Add a `transit` to inspect or modify computed results from
+    /// downstream. Transits are called in the order of addition.
+    pub fn add_transit(&mut self, transit: impl Transit + 'static) {
+        self.transits.push(Box::new(transit));
+    }
+
+    /// Forward requests from `upstream` i-PI server to `downstream` driver,
+    /// until upstream asks to exit or closes the connection.
+    pub async fn run(&mut self, upstream: IpiStream, mut downstream: IpiStream) -> Result<()> {
+        // NOTE: downstream driver will be initialized when computing the
+        // first molecule
+        let (read, write) = upstream.into_inner();
+        let ret = self.serve_upstream(IpiDriverStream::new(read, write, self.codec), &mut downstream).await;
+        downstream.shutdown().await;
+
+        ret
+    }
+
+    async fn serve_upstream<R, W>(&mut self, mut upstream: IpiDriverStream<R, W>, downstream: &mut IpiStream) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut initialized = false;
+        let mut computed: Option<Computed> = None;
+        while let Some(msg) = upstream.recv().await? {
+            match msg {
+                ServerMessage::Status => {
+                    let status = if !initialized {
+                        ClientStatus::NeedInit
+                    } else if computed.is_some() {
+                        ClientStatus::HaveData
+                    } else {
+                        ClientStatus::Ready
+                    };
+                    upstream.send(ClientMessage::Status(status)).await?;
+                }
+                ServerMessage::Init(init) => {
+                    debug!("upstream init: {init:?}");
+                    initialized = true;
+                }
+                ServerMessage::PosData(mol) => {
+                    let mol = match &self.template {
+                        Some(template) => merge_positions(template, &mol)?,
+                        None => mol,
+                    };
+                    let mut c = downstream.compute_one(mol.clone()).await?;
+                    for t in self.transits.iter_mut() {
+                        t.transit(&mol, &mut c)?;
+                    }
+                    computed = Some(c);
+                }
+                ServerMessage::GetForce => {
+                    let c = computed.take().ok_or(format_err!("upstream asks for forces before POSDATA"))?;
+                    upstream.send(ClientMessage::ForceReady(c)).await?;
+                    // NOTE: ask for init again before next step, following
+                    // ASE's SocketClient
+                    initialized = false;
+                }
+                ServerMessage::Exit => {
+                    info!("upstream asks to exit");
+                    return Ok(());
+                }
+            }
+        }
+        info!("upstream closed the connection");
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_transit_chain() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    // transits applied in the order of addition
+    struct Double;
+    impl Transit for Double {
+        fn transit(&mut self, _mol: &Molecule, computed: &mut Computed) -> Result<()> {
+            computed.set_energy(2.0 * computed.energy());
+            Ok(())
+        }
+    }
+    struct Collect(Arc<Mutex<Vec<(String, f64)>>>);
+    impl Transit for Collect {
+        fn transit(&mut self, mol: &Molecule, computed: &mut Computed) -> Result<()> {
+            self.0.lock().unwrap().push((mol.title(), computed.energy()));
+            Ok(())
+        }
+    }
+
+    // upstream i-PI server asking for one step
+    async fn fake_server(stream: tokio::io::DuplexStream, mol: Molecule) -> std::io::Result<Vec<ClientMessage>> {
+        let (read, write) = tokio::io::split(stream);
+        let mut read = FramedRead::new(read, codec::ClientCodec::default());
+        let mut write = FramedWrite::new(write, codec::ServerCodec::default());
+        let mut replies = vec![];
+        let steps = vec![
+            ServerMessage::Status,
+            ServerMessage::Init(InitData::new(0, "\0")),
+            ServerMessage::Status,
+            ServerMessage::PosData(mol),
+            ServerMessage::Status,
+            ServerMessage::GetForce,
+        ];
+        for msg in steps {
+            let reply = matches!(msg, ServerMessage::Status | ServerMessage::GetForce);
+            write.send(msg).await?;
+            if reply {
+                replies.push(read.next().await.unwrap()?);
+            }
+        }
+        write.send(ServerMessage::Exit).await?;
+        Ok(replies)
+    }
+
+    // downstream driver returning unit forces along x
+    let unit_forces = |mol: &Molecule| Computed {
+        energy: -1.0,
+        forces: vec![[1.0, 0.0, 0.0]; mol.natoms()],
+        virial: [0.0; 9],
+        extra: String::new(),
+    };
+
+    let mut template = Molecule::from_file("tests/files/quinone.cif")?;
+    template.set_title("template");
+    let (up_server, up_bridge) = tokio::io::duplex(1 << 16);
+    let (down_bridge, down_driver) = tokio::io::duplex(1 << 16);
+    let server = tokio::spawn(fake_server(up_server, template.clone()));
+    let driver = tokio::spawn(ipi::fake_driver(down_driver, unit_forces));
+
+    let log = std::env::temp_dir().join(format!("gosh-ipi-transit-{}.log", std::process::id()));
+    let collected = Arc::new(Mutex::new(vec![]));
+    let mut bridge = Bridge::new(Some(template));
+    bridge.add_transit(Double);
+    bridge.add_transit(Collect(collected.clone()));
+    bridge.add_transit(TransitLog::create(&log)?);
+    bridge.run(IpiStream::from_stream(up_bridge), IpiStream::from_stream(down_bridge)).await?;
+
+    // the transits see the template metadata and the doubled energy
+    assert_eq!(*collected.lock().unwrap(), vec![("template".to_owned(), -2.0)]);
+    let replies = server.await??;
+    match replies.last() {
+        Some(ClientMessage::ForceReady(c)) => assert_eq!(c.energy, -2.0),
+        x => panic!("unexpected reply: {x:?}"),
+    }
+    driver.await??;
+    let logged = std::fs::read_to_string(&log)?;
+    let _ = std::fs::remove_file(&log);
+    assert_eq!(logged.lines().count(), 2);
+    assert!(logged.lines().nth(1).unwrap().contains("-2.00000000"));
+
+    Ok(())
+}
+// 6fc81436 ends here
+
+// [[file:../ipi.note::3b9e62d0][3b9e62d0]]
+#[tokio::test]
+async fn test_ase_conventions() -> Result<()> {
+    // NOTE: the reference frames are synthesized by
+    // tests/files/ase-session.py following ASE's framing, not captured from
+    // ASE
+    use approx::*;
+    use bytes::BytesMut;
+    use socket::Socket;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio_util::codec::Decoder;
+
+    // split reference byte stream into messages and their raw frames
+    fn split_frames<D: Decoder>(data: &[u8], mut codec: D) -> Vec<(D::Item, Vec<u8>)>
+    where
+        D::Error: std::fmt::Debug,
+    {
+        let mut src = BytesMut::from(data);
+        let mut frames = vec![];
+        loop {
+            let start = data.len() - src.len();
+            match codec.decode(&mut src).unwrap() {
+                Some(msg) => frames.push((msg, data[start..data.len() - src.len()].to_vec())),
+                None => break,
+            }
+        }
+        assert!(src.is_empty());
+        frames
+    }
+
+    // replay reference frames of ASE's SocketIOCalculator, and collect the
+    // replies from driver
+    async fn fake_ase_server(listener: UnixListener, frames: Vec<(ServerMessage, Vec<u8>)>) -> Vec<ClientMessage> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut read = FramedRead::new(read, codec::ClientCodec::default());
+        let mut replies = vec![];
+        for (msg, bytes) in frames {
+            write.write_all(&bytes).await.unwrap();
+            if matches!(msg, ServerMessage::Status | ServerMessage::GetForce) {
+                replies.push(read.next().await.unwrap().unwrap());
+            }
+        }
+        replies
+    }
+
+    // emulate ASE's SocketClient, replying reference forces, and collect the
+    // messages from server
+    async fn fake_ase_client(path: String, forces: Vec<Vec<u8>>) -> Vec<ServerMessage> {
+        let stream = UnixStream::connect(path).await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut read = FramedRead::new(read, codec::ServerCodec::default());
+        let mut forces = forces.into_iter();
+        let mut state = "NEEDINIT";
+        let mut received = vec![];
+        while let Some(msg) = read.next().await {
+            let msg = msg.unwrap();
+            match &msg {
+                ServerMessage::Status => write.write_all(format!("{state:12}").as_bytes()).await.unwrap(),
+                ServerMessage::Init(_) => {
+                    assert_eq!(state, "NEEDINIT");
+                    state = "READY";
+                }
+                ServerMessage::PosData(_) => {
+                    assert_eq!(state, "READY");
+                    state = "HAVEDATA";
+                }
+                ServerMessage::GetForce => {
+                    assert_eq!(state, "HAVEDATA");
+                    write.write_all(&forces.next().unwrap()).await.unwrap();
+                    state = "NEEDINIT";
+                }
+                ServerMessage::Exit => {}
+            }
+            let exit = matches!(msg, ServerMessage::Exit);
+            received.push(msg);
+            if exit {
+                break;
+            }
+        }
+        received
+    }
+
+    let server_frames = split_frames(&std::fs::read("tests/files/ase-server.dat")?, codec::ServerCodec::default());
+    let client_frames = split_frames(&std::fs::read("tests/files/ase-client.dat")?, codec::ClientCodec::default());
+    let forces: Vec<_> = client_frames
+        .iter()
+        .filter(|(msg, _)| matches!(msg, ClientMessage::ForceReady(_)))
+        .map(|(_, bytes)| bytes.clone())
+        .collect();
+
+    // ASE in server role: SocketIOCalculator listens on /tmp/ipi_{name}
+    let pid = std::process::id();
+    let upstream = format!("gosh-ipi-ase-up-{pid}");
+    let upstream_file = format!("/tmp/ipi_{upstream}");
+    let _ = std::fs::remove_file(&upstream_file);
+    let listener = UnixListener::bind(&upstream_file)?;
+    let ase_server = tokio::spawn(fake_ase_server(listener, server_frames.clone()));
+
+    // ASE in driver role: SocketClient connects to our i-PI server
+    let downstream = format!("gosh-ipi-ase-down-{pid}");
+    let ipi_server = Socket::bind(&downstream, 0, true).await?;
+    let ase_client = tokio::spawn(fake_ase_client(format!("/tmp/ipi_{downstream}"), forces));
+
+    let downstream = ipi_server.accept().await?;
+    let upstream = Socket::connect(&upstream, 0, true).await?;
+    Bridge::new(None).run(upstream, downstream).await?;
+
+    // our driver replies in the same way as ASE's SocketClient
+    let replies = ase_server.await?;
+    let reference: Vec<_> = client_frames.into_iter().map(|(msg, _)| msg).collect();
+    assert_eq!(replies.len(), reference.len());
+    for (x, y) in replies.iter().zip(&reference) {
+        match (x, y) {
+            (ClientMessage::Status(s1), ClientMessage::Status(s2)) => assert_eq!(s1, s2),
+            (ClientMessage::ForceReady(c1), ClientMessage::ForceReady(c2)) => {
+                assert_relative_eq!(c1.energy, c2.energy, epsilon = 1e-8);
+                for (f1, f2) in c1.forces.iter().zip(&c2.forces) {
+                    for k in 0..3 {
+                        assert_relative_eq!(f1[k], f2[k], epsilon = 1e-8);
+                    }
+                }
+            }
+            _ => panic!("inconsistent reply: {x:?} vs {y:?}"),
+        }
+    }
+
+    // our server sends messages in the same order as ASE's SocketIOCalculator
+    let received = ase_client.await?;
+    let reference: Vec<_> = server_frames.into_iter().map(|(msg, _)| msg).collect();
+    let kinds = |msgs: &[ServerMessage]| msgs.iter().map(std::mem::discriminant).collect::<Vec<_>>();
+    assert_eq!(kinds(&received), kinds(&reference));
+    for (x, y) in received.iter().zip(&reference) {
+        if let (ServerMessage::PosData(m1), ServerMessage::PosData(m2)) = (x, y) {
+            for (p1, p2) in m1.positions().zip(m2.positions()) {
+                for k in 0..3 {
+                    assert_relative_eq!(p1[k], p2[k], epsilon = 1e-8);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+// 3b9e62d0 ends here
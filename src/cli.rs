@@ -34,16 +34,34 @@ struct ProxyClient {
     /// The file containing molecule for computation
     mol_file: PathBuf,
 
-    /// Path to lock file containing server address for connection
-    #[clap(short = 'w', default_value = "gosh-ipi.lock")]
-    lock_file: PathBuf,
+    /// Path to lock file containing server address for connection. Can be
+    /// given multiple times for failing over to other servers.
+    #[clap(short = 'w', default_value = "gosh-ipi.lock", multiple_occurrences = true)]
+    lock_file: Vec<PathBuf>,
+
+    /// Wait at most this number of seconds for lock file of a starting
+    /// server
+    #[clap(long, default_value = "2")]
+    wait: f64,
+
+    /// Max number of retries on connection failures
+    #[clap(long, default_value = "5")]
+    retries: usize,
+
+    /// Exchange compact binary payloads with server instead of JSON
+    #[clap(long)]
+    binary: bool,
 }
 
 impl ProxyClient {
     fn enter_main(&self) -> Result<()> {
-        LockFile::wait(&self.lock_file, 2.0)?;
-        let addr = gut::fs::read_file(&self.lock_file)?;
-        let client = rest::Client::connect(addr.trim());
+        let retry = rest::RetryOptions {
+            max_retries: self.retries,
+            ..Default::default()
+        };
+        let client = rest::Client::from_lock_files(&self.lock_file, self.wait)?
+            .with_binary(self.binary)
+            .with_retry(retry);
         let mol = Molecule::from_file(&self.mol_file)?;
         let mp = client.compute_molecule(&mol)?;
         println!("{mp}");
@@ -59,16 +77,433 @@ struct ProxyServer {
     /// Path to lock file for writing server address.
     #[clap(short = 'w', default_value = "gosh-ipi.lock")]
     lock_file: PathBuf,
+
+    /// The host name for i-PI server to listen on, or the name of unix
+    /// domain socket
+    #[clap(long, default_value = "localhost")]
+    host: String,
+
+    /// The port for i-PI server to listen on
+    #[clap(long, default_value = "12345")]
+    port: u16,
+
+    /// Use unix domain socket instead of internet socket
+    #[clap(short = 'u')]
+    unix: bool,
+
+    /// Spawn driver using this shell command, and talk to it in i-PI
+    /// protocol over stdin/stdout pipes instead of socket
+    #[clap(long, conflicts_with = "unix")]
+    pipe: Option<String>,
+
+    /// Encrypt i-PI connections with TLS using certificate in this PEM
+    /// file, for drivers on other nodes
+    #[clap(long, requires_all = &["tls_key", "tls_ca"], conflicts_with_all = &["unix", "pipe"])]
+    tls_cert: Option<PathBuf>,
+
+    /// The private key in PEM file for TLS
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// The CA certificates in PEM file for verifying drivers' certificates
+    #[clap(long, requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+
+    /// Poll idle drivers with STATUS in this interval in seconds, and
+    /// remove drivers not answering in time
+    #[clap(long)]
+    heartbeat: Option<f64>,
+
+    /// The time in seconds to wait for drivers answering heartbeat STATUS
+    #[clap(long, requires = "heartbeat")]
+    heartbeat_timeout: Option<f64>,
+
+    /// Idle time in seconds before sending the first TCP keepalive probe
+    /// to drivers on internet socket
+    #[clap(long, default_value = "60")]
+    keepalive_time: f64,
+
+    /// Interval in seconds between TCP keepalive probes
+    #[clap(long, default_value = "10")]
+    keepalive_interval: f64,
+
+    /// Number of unanswered TCP keepalive probes before dropping driver
+    #[clap(long, default_value = "6")]
+    keepalive_retries: u32,
+
+    /// The byte order of numbers in i-PI messages: little, big or native
+    #[clap(long, default_value = "little")]
+    byte_order: codec::ByteOrder,
+
+    /// The size of integers in i-PI messages in bytes: 4 as in i-PI, or 8
+    /// for drivers built with 8-byte default integers
+    #[clap(long, default_value = "4", possible_values = &["4", "8"])]
+    int_size: usize,
+
+    /// Reject i-PI messages with more atoms than this number
+    #[clap(long, default_value = "10000000")]
+    max_atoms: usize,
+
+    /// Reject INIT messages with initialization string larger than this
+    /// number of bytes
+    #[clap(long, default_value = "1048576")]
+    max_init: usize,
+
+    /// Reject FORCEREADY messages with extra data larger than this number
+    /// of bytes
+    #[clap(long, default_value = "67108864")]
+    max_extra: usize,
+
+    /// Require token for accessing REST service. The token is generated at
+    /// startup, and stored in lock file for clients.
+    #[clap(long)]
+    auth: bool,
+
+    /// Serve REST service on unix domain socket at this path, instead of a
+    /// free TCP port. The socket file is only accessible by its owner.
+    #[clap(long)]
+    rest_socket: Option<PathBuf>,
+
+    /// Path to JSON file defining restraints for external bias potential
+    #[clap(long)]
+    bias: Option<PathBuf>,
+
+    /// Path to JSON file defining constraints (frozen atoms, fixed
+    /// components, rigid fragments) imposed on computed forces
+    #[clap(long, conflicts_with = "freeze")]
+    constraints: Option<PathBuf>,
+
+    /// Freeze atoms in this selection (e.g. "1-16,20") by zeroing their
+    /// forces
+    #[clap(long)]
+    freeze: Option<String>,
+
+    /// Path to JSON file defining a composite model (ONIOM, mixing or
+    /// Δ-learning) combining several drivers
+    #[clap(long, conflicts_with = "ensemble")]
+    composite: Option<PathBuf>,
+
+    /// Run committee models: broadcast each molecule to this number of
+    /// drivers, and return the mean energy and forces with uncertainty
+    #[clap(long)]
+    ensemble: Option<usize>,
+
+    /// Flag structures with max force deviation (eV/Å) in committee larger
+    /// than this threshold as uncertain
+    #[clap(long, requires = "ensemble")]
+    threshold: Option<f64>,
+
+    /// Append uncertain structures into this file for later labelling
+    #[clap(long, requires = "threshold")]
+    record_uncertain: Option<PathBuf>,
+
+    /// Record each computed structure with energy and forces into this
+    /// extended XYZ file as training data
+    #[clap(long)]
+    record: Option<PathBuf>,
+
+    /// Rotate the record file when its size exceeds this number of MB
+    #[clap(long, requires = "record")]
+    record_max_size: Option<u64>,
+
+    /// Skip recording structures with the same geometry after positions
+    /// are rounded on a grid with this spacing in Å
+    #[clap(long, requires = "record")]
+    record_dedup: Option<f64>,
 }
 
 impl ProxyServer {
     fn enter_main(&self) -> Result<()> {
-        rest::Server::enter_main(&self.lock_file)?;
+        let bias = self.bias.as_deref().map(bias::BiasPotential::from_file).transpose()?;
+        let constraints = constraints(&self.constraints, &self.freeze)?;
+        let composite = self.composite.as_deref().map(composite::CompositeConfig::from_file).transpose()?;
+        let ensemble = self.ensemble.map(|nmodels| ensemble::EnsembleOptions {
+            nmodels,
+            threshold: self.threshold,
+            record: self.record_uncertain.clone(),
+        });
+        let recorder = self.record.as_ref().map(|path| recorder::RecorderOptions {
+            path: path.to_owned(),
+            max_size: self.record_max_size.map(|mb| mb * 1024 * 1024),
+            dedup: self.record_dedup,
+        });
+        let ipi = composite::DriverConfig {
+            host: self.host.clone(),
+            port: self.port,
+            unix: self.unix,
+            pipe: self.pipe.clone(),
+            tls: tls_options(&self.tls_cert, &self.tls_key, &self.tls_ca),
+            heartbeat: self.heartbeat,
+            heartbeat_timeout: self.heartbeat_timeout,
+            keepalive: socket::KeepaliveOptions {
+                time: self.keepalive_time,
+                interval: self.keepalive_interval,
+                retries: self.keepalive_retries,
+            },
+            codec: codec::CodecOptions {
+                byte_order: self.byte_order,
+                int_size: self.int_size,
+                max_atoms: self.max_atoms,
+                max_init: self.max_init,
+                max_extra: self.max_extra,
+            },
+        };
+        let options = rest::ServerOptions {
+            ipi,
+            auth: self.auth,
+            rest_socket: self.rest_socket.clone(),
+            bias,
+            constraints,
+            composite,
+            ensemble,
+            recorder,
+        };
+        rest::Server::enter_main(&self.lock_file, options)?;
         Ok(())
     }
 }
+
+/// Collect TLS options from command line arguments, which are all required
+/// when TLS enabled.
+fn tls_options(cert: &Option<PathBuf>, key: &Option<PathBuf>, ca: &Option<PathBuf>) -> Option<tls::TlsOptions> {
+    Some(tls::TlsOptions {
+        cert: cert.clone()?,
+        key: key.clone()?,
+        ca: ca.clone()?,
+        server_name: None,
+    })
+}
+
+/// Read constraints from JSON file at `path`, or freeze atoms in selection
+/// `freeze`.
+fn constraints(path: &Option<PathBuf>, freeze: &Option<String>) -> Result<Option<constraint::Constraints>> {
+    let constraints = match (path, freeze) {
+        (Some(path), _) => constraint::Constraints::from_file(path)?,
+        (None, Some(freeze)) => {
+            let constraints = constraint::Constraints {
+                fixed: Some(freeze.to_owned()),
+                ..Default::default()
+            };
+            constraints.check()?;
+            constraints
+        }
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(constraints))
+}
 // cf06c8c7 ends here
 
+// [[file:../ipi.note::e5d00617][e5d00617]]
+#[derive(Args, Debug)]
+/// Compute Hessian matrix and harmonic frequencies of molecule by finite
+/// difference, using any package (CP2K, SIESTA, etc) in i-PI protocol
+struct ProxyHessian {
+    /// The file containing molecule for computation
+    mol_file: PathBuf,
+
+    /// The displacement step size in Å
+    #[clap(long, default_value = "0.01")]
+    delta: f64,
+
+    /// Max number of displacements computed in parallel. Set it to the
+    /// number of connected drivers.
+    #[clap(short = 'j', default_value = "1")]
+    nparallel: usize,
+
+    /// Path to checkpoint file for restarting from partial results. It will
+    /// be removed after the results are written successfully.
+    #[clap(long, default_value = "hessian-ckpt.json")]
+    checkpoint: PathBuf,
+
+    /// Path to output file for writing vibrational analysis results in json
+    #[clap(short = 'o', default_value = "hessian.json")]
+    output: PathBuf,
+
+    /// The host name for i-PI server to listen on, or the name of unix
+    /// domain socket
+    #[clap(long, default_value = "localhost")]
+    host: String,
+
+    /// The port for i-PI server to listen on
+    #[clap(long, default_value = "12345")]
+    port: u16,
+
+    /// Use unix domain socket instead of internet socket
+    #[clap(short = 'u')]
+    unix: bool,
+}
+
+impl ProxyHessian {
+    #[tokio::main]
+    async fn enter_main(&self) -> Result<()> {
+        use hessian::*;
+
+        let mol = Molecule::from_file(&self.mol_file)?;
+        let ipi_server = Socket::bind(&self.host, self.port, self.unix).await?;
+        let (task_rx, task_tx) = task::Task::new().split();
+        let h = tokio::spawn(async move { ipi_server.serve_channel(task_rx).await });
+
+        let options = HessianOptions {
+            delta: self.delta,
+            nparallel: self.nparallel,
+            checkpoint: self.checkpoint.clone().into(),
+        };
+        let vib = compute_hessian(&task_tx, &mol, &options).await?;
+        // let drivers exit
+        drop(task_tx);
+        h.await??;
+
+        println!("{:^6} {:>12}", "mode", "freq/cm-1");
+        for (i, f) in vib.frequencies.iter().enumerate() {
+            println!("{:^6} {:>12.2}", i + 1, f);
+        }
+        println!("zero-point energy = {:.6} eV", vib.zpe);
+        let json = serde_json::to_string_pretty(&vib)?;
+        write_to_file(&self.output, &json)?;
+        println!("hessian, frequencies and normal modes written to {:?}", self.output);
+        // the checkpoint is useless now, and would be rejected by the next
+        // run on another structure
+        if self.checkpoint.exists() {
+            std::fs::remove_file(&self.checkpoint).with_context(|| format!("remove checkpoint {:?}", self.checkpoint))?;
+        }
+
+        Ok(())
+    }
+}
+// e5d00617 ends here
+
+// [[file:../ipi.note::5dad90f8][5dad90f8]]
+#[derive(Args, Debug)]
+/// Bridge between an upstream i-PI server and a downstream driver: act as a
+/// driver towards upstream, and forward each request to the external code
+/// connected to our own i-PI server
+struct ProxyBridge {
+    /// The host name of upstream i-PI server to connect, or the name of unix
+    /// domain socket
+    #[clap(long, default_value = "localhost")]
+    upstream_host: String,
+
+    /// The port of upstream i-PI server to connect
+    #[clap(long, default_value = "31415")]
+    upstream_port: u16,
+
+    /// Connect to upstream i-PI server using unix domain socket
+    #[clap(long, conflicts_with = "upstream_tls_cert")]
+    upstream_unix: bool,
+
+    /// Connect to upstream i-PI server with TLS, using certificate in this
+    /// PEM file
+    #[clap(long, requires_all = &["upstream_tls_key", "upstream_tls_ca"])]
+    upstream_tls_cert: Option<PathBuf>,
+
+    /// The private key in PEM file for TLS connection to upstream
+    #[clap(long, requires = "upstream_tls_cert")]
+    upstream_tls_key: Option<PathBuf>,
+
+    /// The CA certificates in PEM file for verifying upstream server
+    #[clap(long, requires = "upstream_tls_cert")]
+    upstream_tls_ca: Option<PathBuf>,
+
+    /// The byte order of numbers in messages from/to upstream: little, big
+    /// or native
+    #[clap(long, default_value = "little")]
+    upstream_byte_order: codec::ByteOrder,
+
+    /// The size of integers in messages from/to upstream in bytes: 4 or 8
+    #[clap(long, default_value = "4", possible_values = &["4", "8"])]
+    upstream_int_size: usize,
+
+    /// The host name for our i-PI server to listen on, or the name of unix
+    /// domain socket
+    #[clap(long, default_value = "localhost")]
+    host: String,
+
+    /// The port for our i-PI server to listen on
+    #[clap(long, default_value = "12345")]
+    port: u16,
+
+    /// Use unix domain socket for our i-PI server
+    #[clap(short = 'u')]
+    unix: bool,
+
+    /// The byte order of numbers in messages from/to downstream driver:
+    /// little, big or native
+    #[clap(long, default_value = "little")]
+    byte_order: codec::ByteOrder,
+
+    /// The size of integers in messages from/to downstream driver in
+    /// bytes: 4 or 8
+    #[clap(long, default_value = "4", possible_values = &["4", "8"])]
+    int_size: usize,
+
+    /// The molecule file for fixing the species of structures from upstream
+    #[clap(short = 't')]
+    template: Option<PathBuf>,
+
+    /// Log energy and max force of each step into this file
+    #[clap(long)]
+    log: Option<PathBuf>,
+
+    /// Path to JSON file defining restraints for external bias potential
+    /// added on forces in transit
+    #[clap(long)]
+    bias: Option<PathBuf>,
+
+    /// Path to JSON file defining constraints imposed on forces in transit.
+    /// Frozen atoms in template can be used with `from_molecule`.
+    #[clap(long, conflicts_with = "freeze")]
+    constraints: Option<PathBuf>,
+
+    /// Freeze atoms in this selection (e.g. "1-16,20") by zeroing their
+    /// forces in transit
+    #[clap(long)]
+    freeze: Option<String>,
+}
+
+impl ProxyBridge {
+    #[tokio::main]
+    async fn enter_main(&self) -> Result<()> {
+        use bridge::*;
+
+        let template = self.template.as_ref().map(Molecule::from_file).transpose()?;
+        let mut bridge = Bridge::new(template);
+        let upstream_codec = codec::CodecOptions {
+            byte_order: self.upstream_byte_order,
+            int_size: self.upstream_int_size,
+            ..Default::default()
+        };
+        upstream_codec.check()?;
+        bridge.set_codec(upstream_codec);
+        let codec = codec::CodecOptions {
+            byte_order: self.byte_order,
+            int_size: self.int_size,
+            ..Default::default()
+        };
+        codec.check()?;
+        if let Some(path) = &self.bias {
+            bridge.add_transit(bias::BiasPotential::from_file(path)?);
+        }
+        if let Some(constraints) = constraints(&self.constraints, &self.freeze)? {
+            bridge.add_transit(constraints);
+        }
+        if let Some(log) = &self.log {
+            bridge.add_transit(TransitLog::create(log)?);
+        }
+
+        let ipi_server = Socket::bind(&self.host, self.port, self.unix).await?.with_codec(codec);
+        info!("wait for downstream driver to connect ...");
+        let downstream = ipi_server.accept().await?;
+        let upstream = match tls_options(&self.upstream_tls_cert, &self.upstream_tls_key, &self.upstream_tls_ca) {
+            Some(tls) => Socket::connect_tls(&self.upstream_host, self.upstream_port, &tls).await?,
+            None => Socket::connect(&self.upstream_host, self.upstream_port, self.upstream_unix).await?,
+        };
+        bridge.run(upstream, downstream).await?;
+
+        Ok(())
+    }
+}
+// 5dad90f8 ends here
+
 // [[file:../ipi.note::34481538][34481538]]
 #[derive(Subcommand, Debug)]
 enum ProxyCmd {
@@ -76,6 +511,10 @@ enum ProxyCmd {
     Client(ProxyClient),
     /// Server side action for ipi-proxy
     Server(ProxyServer),
+    /// Finite-difference Hessian and vibrational analysis
+    Hessian(ProxyHessian),
+    /// Bridge between an upstream i-PI server and a downstream driver
+    Bridge(ProxyBridge),
 }
 
 #[derive(Debug, Parser)]
@@ -98,6 +537,8 @@ impl IpiProxyCli {
         match args.cmd {
             ProxyCmd::Client(client) => client.enter_main()?,
             ProxyCmd::Server(server) => server.enter_main()?,
+            ProxyCmd::Hessian(hessian) => hessian.enter_main()?,
+            ProxyCmd::Bridge(bridge) => bridge.enter_main()?,
         }
 
         Ok(())
@@ -2,7 +2,8 @@
 use super::*;
 
 use bytes::{Buf, BufMut};
-use bytes::{Bytes, BytesMut};
+use bytes::BytesMut;
+use serde::{Deserialize, Serialize};
 // d2086cfc ends here
 
 // [[file:../ipi.note::1a9eabbb][1a9eabbb]]
@@ -12,6 +13,402 @@ type EncodedResult = Result<(), std::io::Error>;
 const HEADER_SIZE: usize = 12;
 // 1a9eabbb ends here
 
+// [[file:../ipi.note::5c0b7e21][5c0b7e21]]
+/// The byte order of numbers in i-PI messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteOrder {
+    /// Little endian, as in i-PI and ASE
+    #[default]
+    Little,
+    /// Big endian
+    Big,
+    /// The byte order of this machine, for drivers sending native-endian
+    /// data
+    Native,
+}
+
+impl std::str::FromStr for ByteOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "little" => Ok(Self::Little),
+            "big" => Ok(Self::Big),
+            "native" => Ok(Self::Native),
+            _ => Err(format!("invalid byte order: {s}, expect little, big or native")),
+        }
+    }
+}
+
+impl ByteOrder {
+    fn is_big(self) -> bool {
+        match self {
+            Self::Little => false,
+            Self::Big => true,
+            Self::Native => cfg!(target_endian = "big"),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        if self.is_big() {
+            "big"
+        } else {
+            "little"
+        }
+    }
+
+    fn swapped(self) -> Self {
+        if self.is_big() {
+            Self::Little
+        } else {
+            Self::Big
+        }
+    }
+}
+
+fn default_int_size() -> usize {
+    4
+}
+
+fn default_max_atoms() -> usize {
+    10_000_000
+}
+
+fn default_max_init() -> usize {
+    1 << 20
+}
+
+fn default_max_extra() -> usize {
+    64 << 20
+}
+
+/// The binary layout of numbers in i-PI messages. The i-PI protocol uses
+/// little-endian f64 and 4-byte integers, but some Fortran drivers send
+/// native-endian data on big-endian machines, or 8-byte integers when
+/// built with default integer size of 8.
+///
+/// Length fields beyond the limits are rejected as protocol error before
+/// reading the data, so that a buggy or malicious peer can not exhaust
+/// memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodecOptions {
+    /// The byte order of integers and floats
+    #[serde(default)]
+    pub byte_order: ByteOrder,
+    /// The size of integers in bytes: 4 or 8
+    #[serde(default = "default_int_size")]
+    pub int_size: usize,
+    /// Max number of atoms in POSDATA or FORCEREADY
+    #[serde(default = "default_max_atoms")]
+    pub max_atoms: usize,
+    /// Max size of INIT string in bytes
+    #[serde(default = "default_max_init")]
+    pub max_init: usize,
+    /// Max size of extra data in FORCEREADY in bytes
+    #[serde(default = "default_max_extra")]
+    pub max_extra: usize,
+}
+
+impl Default for CodecOptions {
+    fn default() -> Self {
+        Self {
+            byte_order: ByteOrder::default(),
+            int_size: default_int_size(),
+            max_atoms: default_max_atoms(),
+            max_init: default_max_init(),
+            max_extra: default_max_extra(),
+        }
+    }
+}
+
+impl CodecOptions {
+    /// Check if options are valid.
+    pub fn check(&self) -> Result<()> {
+        ensure!(
+            matches!(self.int_size, 4 | 8),
+            "invalid integer size: {}, expect 4 or 8",
+            self.int_size
+        );
+        Ok(())
+    }
+
+    /// Read integer in `bytes` of `int_size` long.
+    fn read_int(&self, mut bytes: &[u8]) -> usize {
+        match (self.int_size, self.byte_order.is_big()) {
+            (8, false) => bytes.get_u64_le() as usize,
+            (8, true) => bytes.get_u64() as usize,
+            (_, false) => bytes.get_u32_le() as usize,
+            (_, true) => bytes.get_u32() as usize,
+        }
+    }
+
+    fn get_int(&self, src: &mut BytesMut) -> usize {
+        let n = self.read_int(&src[..self.int_size]);
+        src.advance(self.int_size);
+        n
+    }
+
+    fn put_int(&self, dst: &mut BytesMut, n: usize) {
+        match (self.int_size, self.byte_order.is_big()) {
+            (8, false) => dst.put_u64_le(n as u64),
+            (8, true) => dst.put_u64(n as u64),
+            (_, false) => dst.put_u32_le(n as u32),
+            (_, true) => dst.put_u32(n as u32),
+        }
+    }
+
+    fn get_f64(&self, src: &mut BytesMut) -> f64 {
+        if self.byte_order.is_big() {
+            src.get_f64()
+        } else {
+            src.get_f64_le()
+        }
+    }
+
+    fn put_f64(&self, dst: &mut BytesMut, x: f64) {
+        if self.byte_order.is_big() {
+            dst.put_f64(x)
+        } else {
+            dst.put_f64_le(x)
+        }
+    }
+
+    /// Decode `n` vectors of three f64 in bulk from `src` into `xs`, scaled
+    /// by `factor`. `xs` is cleared first, so its buffer can be reused across
+    /// messages.
+    fn get_vec3s_into(&self, src: &mut BytesMut, n: usize, factor: f64, xs: &mut Vec<[f64; 3]>) {
+        let big = self.byte_order.is_big();
+        let read = |b: &[u8]| {
+            let b = b.try_into().expect("f64 bytes");
+            let x = if big { f64::from_be_bytes(b) } else { f64::from_le_bytes(b) };
+            x * factor
+        };
+        let nbytes = 24 * n;
+        xs.clear();
+        xs.reserve(n);
+        xs.extend(src[..nbytes].chunks_exact(24).map(|v| [read(&v[..8]), read(&v[8..16]), read(&v[16..])]));
+        src.advance(nbytes);
+    }
+
+    /// Decode `n` vectors of three f64 in bulk from `src`, scaled by
+    /// `factor`, into a new vector allocated once in its final size, for
+    /// data handed over to the decoded message.
+    fn get_vec3s(&self, src: &mut BytesMut, n: usize, factor: f64) -> Vec<[f64; 3]> {
+        let mut xs = Vec::new();
+        self.get_vec3s_into(src, n, factor, &mut xs);
+        xs
+    }
+
+    /// Encode vectors of three f64 in `xs` into `dst`, scaled by `factor`.
+    /// `dst` should be reserved for the whole message by caller.
+    fn put_vec3s(&self, dst: &mut BytesMut, xs: impl IntoIterator<Item = [f64; 3]>, factor: f64) {
+        let big = self.byte_order.is_big();
+        for v in xs {
+            for x in v {
+                let x = x * factor;
+                dst.put_slice(&if big { x.to_be_bytes() } else { x.to_le_bytes() });
+            }
+        }
+    }
+
+    /// Return the other byte orders and integer sizes the peer may use.
+    fn alternatives(&self) -> [Self; 3] {
+        let other_size = if self.int_size == 8 { 4 } else { 8 };
+        [
+            Self {
+                byte_order: self.byte_order.swapped(),
+                ..*self
+            },
+            Self {
+                int_size: other_size,
+                ..*self
+            },
+            Self {
+                byte_order: self.byte_order.swapped(),
+                int_size: other_size,
+                ..*self
+            },
+        ]
+    }
+
+    /// Describe byte order and integer size for messages.
+    fn describe(&self) -> String {
+        format!("{}-byte {} endian", self.int_size, self.byte_order.name())
+    }
+
+    /// Find the alternative layout in which integer in leading `bytes`
+    /// is accepted by `accept`.
+    fn find_alternative(&self, bytes: &[u8], accept: impl Fn(usize) -> bool) -> Option<(Self, usize)> {
+        self.alternatives().into_iter().find_map(|opts| {
+            let m = opts.read_int(bytes.get(..opts.int_size)?);
+            accept(m).then(|| (opts, m))
+        })
+    }
+
+    /// Explain length `n` of `what` decoded from leading `bytes` beyond
+    /// limit, which is usually caused by mismatched byte order or integer
+    /// size, rather than a really huge message.
+    fn diagnose_length(&self, bytes: &[u8], n: usize, max: usize, what: &str) -> String {
+        match self.find_alternative(bytes, |m| m <= max) {
+            Some((opts, m)) => {
+                let this = self.describe();
+                let other = opts.describe();
+                format!(
+                    "implausible {what}: {n} in {this}, but {m} in {other}; \
+                     the peer seems to send integers in {other}, please set byte order and integer size accordingly"
+                )
+            }
+            None => format!("{what} exceeds limit: {n} > {max}; the i-PI message is corrupted or too large"),
+        }
+    }
+
+    /// Explain number of atoms `n` of `what` decoded from leading `bytes`,
+    /// different from the `expected` number of atoms sent to the peer.
+    fn diagnose_natoms(&self, bytes: &[u8], n: usize, expected: usize, what: &str) -> String {
+        match self.find_alternative(bytes, |m| m == expected) {
+            Some((opts, _)) => {
+                let this = self.describe();
+                let other = opts.describe();
+                format!(
+                    "unexpected {what}: {n} in {this}, but {expected} atoms were sent, as read in {other}; \
+                     the peer seems to send integers in {other}, please set byte order and integer size accordingly"
+                )
+            }
+            None => format!("unexpected {what}: {n}, but {expected} atoms were sent"),
+        }
+    }
+}
+
+#[test]
+fn test_codec_byte_order() {
+    use tokio_util::codec::Decoder;
+
+    let mol = Molecule::from_file("tests/files/quinone.cif").unwrap();
+    let little = CodecOptions::default();
+    let big = CodecOptions {
+        byte_order: ByteOrder::Big,
+        ..little
+    };
+    let big8 = CodecOptions { int_size: 8, ..big };
+    for opts in [little, big, big8] {
+        let mut dest = BytesMut::new();
+        encode_posdata(&mut dest, &mol, &opts).unwrap();
+        let decoded = decode_posdata(&mut dest, &opts).unwrap();
+        assert_eq!(decoded.natoms(), mol.natoms());
+        assert!(dest.is_empty());
+    }
+
+    // mismatched byte order is reported, instead of waiting for data
+    let mut dest = BytesMut::new();
+    encode_posdata(&mut dest, &mol, &big).unwrap();
+    let err = match decode_posdata(&mut dest, &little) {
+        Err(DecodeError::IoError(e)) => e,
+        _ => panic!("byte order mismatch not detected"),
+    };
+    assert!(err.to_string().contains("big endian"), "{err}");
+
+    // so is mismatched integer size
+    let little8 = CodecOptions { int_size: 8, ..little };
+    let mut dest = BytesMut::new();
+    encode_posdata(&mut dest, &mol, &little).unwrap();
+    let err = match decode_posdata(&mut dest, &little8) {
+        Err(DecodeError::IoError(e)) => e,
+        _ => panic!("integer size mismatch not detected"),
+    };
+    assert!(err.to_string().contains("4-byte little endian"), "{err}");
+
+    let computed = Computed {
+        energy: -1.0,
+        forces: vec![[0.1; 3]; 12],
+        virial: [0.0; 9],
+        extra: String::new(),
+    };
+    let mut dest = BytesMut::new();
+    encode_client_computed(&mut dest, &computed, &big).unwrap();
+    assert!(ClientCodec::default().decode(&mut dest).is_err());
+    match ClientCodec::new(big).decode(&mut dest).unwrap() {
+        Some(ClientMessage::ForceReady(c)) => assert_eq!(c.forces.len(), 12),
+        x => panic!("unexpected message: {x:?}"),
+    }
+
+    // small number of atoms in wrong byte order looks plausible (256 in big
+    // endian is 65536 in little endian), but is caught by checking against
+    // the number of atoms sent in POSDATA
+    let computed = Computed {
+        forces: vec![[0.1; 3]; 256],
+        ..computed
+    };
+    let mut dest = BytesMut::new();
+    encode_client_computed(&mut dest, &computed, &big).unwrap();
+    let mut head = BytesMut::from(&dest[..HEADER_SIZE + 8 + 4]);
+    let mut codec = ClientCodec::default();
+    assert!(codec.clone().decode(&mut head.clone()).unwrap().is_none());
+    codec.expect_natoms(256);
+    let err = codec.decode(&mut head).unwrap_err();
+    assert!(err.to_string().contains("4-byte big endian"), "{err}");
+    let mut codec = ClientCodec::new(big);
+    codec.expect_natoms(256);
+    assert!(codec.decode(&mut dest).unwrap().is_some());
+}
+
+#[test]
+fn test_codec_limits() {
+    use tokio_util::codec::Decoder;
+
+    let opts = CodecOptions {
+        max_atoms: 100,
+        max_init: 16,
+        max_extra: 16,
+        ..Default::default()
+    };
+    // rejected when only the header of huge FORCEREADY arrived
+    let mut src = BytesMut::new();
+    encode_header(&mut src, "FORCEREADY").unwrap();
+    src.put_f64_le(0.0);
+    src.put_u32_le(1_000_000);
+    assert!(ClientCodec::new(opts).decode(&mut src).is_err());
+
+    // buffer is not reserved for the claimed length of a message within
+    // limit, before the data really arrives
+    let mut src = BytesMut::new();
+    encode_header(&mut src, "FORCEREADY").unwrap();
+    src.put_f64_le(0.0);
+    src.put_u32_le(10_000_000);
+    assert!(ClientCodec::default().decode(&mut src).unwrap().is_none());
+    assert!(src.capacity() <= 2 * MAX_RESERVE);
+
+    // INIT string too large
+    let mut src = BytesMut::new();
+    encode_init(&mut src, InitData::new(0, &"x".repeat(17)), &opts).unwrap();
+    assert!(ServerCodec::new(opts).decode(&mut src).is_err());
+
+    // extra data too large
+    let computed = Computed {
+        energy: 0.0,
+        forces: vec![[0.0; 3]; 2],
+        virial: [0.0; 9],
+        extra: "x".repeat(17),
+    };
+    let mut src = BytesMut::new();
+    encode_client_computed(&mut src, &computed, &opts).unwrap();
+    assert!(ClientCodec::new(opts).decode(&mut src).is_err());
+    let computed = Computed {
+        extra: "{}".into(),
+        ..computed
+    };
+    let mut src = BytesMut::new();
+    encode_client_computed(&mut src, &computed, &opts).unwrap();
+    assert!(ClientCodec::new(opts).decode(&mut src).unwrap().is_some());
+
+    // invalid header
+    let mut src = BytesMut::from(&b"HELLO       "[..]);
+    assert!(ClientCodec::default().decode(&mut src).is_err());
+    let mut src = BytesMut::from(&b"HELLO       "[..]);
+    assert!(ServerCodec::default().decode(&mut src).is_err());
+}
+// 5c0b7e21 ends here
+
 // [[file:../ipi.note::1156a769][1156a769]]
 /// A wrapper for Ok(None), so we can early return using question mark (?)
 #[derive(Debug)]
@@ -28,44 +425,101 @@ fn fix_decode_err<T>(e: DecodeError) -> Result<Option<T>, std::io::Error> {
     }
 }
 
-fn try_to_string(bytes: &[u8]) -> Result<String, std::io::Error> {
-    let bytes: Bytes = bytes.into_iter().cloned().collect();
-    String::from_utf8(bytes.to_vec()).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+fn try_to_str(bytes: &[u8]) -> Result<&str, std::io::Error> {
+    std::str::from_utf8(bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
 }
 
-fn to_u32(bytes: &[u8]) -> u32 {
-    assert_eq!(bytes.len(), 4);
-    let mut bytes: Bytes = bytes.into_iter().cloned().collect();
-    bytes.get_u32_le()
+/// Take string of `n` bytes from `src`, ignoring zero-byte padding.
+fn take_string(src: &mut BytesMut, n: usize) -> Result<String, DecodeError> {
+    let s = try_to_str(&src[..n]).map_err(into_decode_error)?;
+    // NOTE: ASE sends one zero byte for empty string
+    let s = s.trim_end_matches('\0').to_owned();
+    src.advance(n);
+    Ok(s)
+}
+
+/// The message header copied out of buffer, without allocation
+struct Header {
+    bytes: [u8; HEADER_SIZE],
+    len: usize,
+}
+
+impl Header {
+    fn as_str(&self) -> &str {
+        // NOTE: validated when decoded
+        std::str::from_utf8(&self.bytes[..self.len]).unwrap_or_default()
+    }
 }
 
 /// Try to decode message header
-fn try_decode_message_header(src: &BytesMut, nheader: usize) -> Result<String, DecodeError> {
+fn try_decode_message_header(src: &BytesMut, nheader: usize) -> Result<Header, DecodeError> {
+    assert_eq!(nheader, HEADER_SIZE);
     if src.len() < nheader {
         return Err(DecodeError::NotEnoughData);
     }
 
-    let s = try_to_string(&src[..nheader]).map_err(|e| into_decode_error(e))?;
-    Ok(s.trim_end().to_string())
+    let mut bytes = [0; HEADER_SIZE];
+    bytes.copy_from_slice(&src[..nheader]);
+    let len = try_to_str(&bytes).map_err(into_decode_error)?.trim_end().len();
+    Ok(Header { bytes, len })
 }
 
-/// Try to decode length header
-fn try_decode_length_header_u32(src: &BytesMut, offset: usize) -> Result<usize, DecodeError> {
-    let nheader = offset + 4;
+/// Try to decode length header of `what` at `offset`. Length larger than
+/// `max` will be rejected early, instead of waiting for the data forever.
+fn try_decode_length_header(
+    src: &BytesMut,
+    offset: usize,
+    opts: &CodecOptions,
+    max: usize,
+    what: &str,
+) -> Result<usize, DecodeError> {
+    let nheader = offset + opts.int_size;
     if src.len() < nheader {
         return Err(DecodeError::NotEnoughData);
     }
-    let n = to_u32(&src[offset..nheader]) as usize;
-    if src.len() < nheader + n {
-        return Err(DecodeError::NotEnoughData);
+    let n = opts.read_int(&src[offset..nheader]);
+    if n > max {
+        let msg = opts.diagnose_length(&src[offset..], n, max, what);
+        error!("{msg}");
+        return Err(into_decode_error(protocol_error(msg)));
     }
 
     Ok(n)
 }
 
-/// Try to read in n bytes
-fn try_decode_nbytes(src: &BytesMut, nbytes: usize) -> Result<(), DecodeError> {
+/// Max bytes reserved in advance for the rest of a message. The message
+/// length is claimed by the peer, so the buffer grows with the data really
+/// received, not with the claimed length.
+const MAX_RESERVE: usize = 1 << 20;
+
+/// Check the number of atoms `n` decoded at `offset` in `src` is the
+/// `expected` one, if known. This catches mismatched byte order or integer
+/// size which gives a plausible but wrong length, before waiting for data
+/// that never arrive.
+fn check_natoms(
+    src: &BytesMut,
+    offset: usize,
+    opts: &CodecOptions,
+    n: usize,
+    expected: Option<usize>,
+    what: &str,
+) -> Result<(), DecodeError> {
+    match expected {
+        Some(expected) if n != expected => {
+            let msg = opts.diagnose_natoms(&src[offset..], n, expected, what);
+            error!("{msg}");
+            Err(into_decode_error(protocol_error(msg)))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Try to read in n bytes. The buffer will be reserved for the rest of the
+/// message up to `MAX_RESERVE`, avoiding repeated reallocation when
+/// receiving large message.
+fn try_decode_nbytes(src: &mut BytesMut, nbytes: usize) -> Result<(), DecodeError> {
     if src.len() < nbytes {
+        src.reserve((nbytes - src.len()).min(MAX_RESERVE));
         Err(DecodeError::NotEnoughData)
     } else {
         Ok(())
@@ -76,16 +530,16 @@ fn into_decode_error(e: std::io::Error) -> DecodeError {
     DecodeError::IoError(e)
 }
 
-fn format_header(code: &str) -> String {
-    let code = format!("{:12}", code);
-    assert_eq!(code.len(), 12);
-    code
+/// Error for messages violating i-PI protocol
+fn protocol_error(msg: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.into())
 }
 
-// Encode simple header str
+// Encode simple header str, padded with spaces
 fn encode_header(dest: &mut BytesMut, header: &str) -> EncodedResult {
-    assert!(header.len() <= 12);
-    dest.put_slice(format_header(header).as_bytes());
+    assert!(header.len() <= HEADER_SIZE);
+    dest.put_slice(header.as_bytes());
+    dest.put_bytes(b' ', HEADER_SIZE - header.len());
 
     Ok(())
 }
@@ -97,32 +551,48 @@ fn encode_client_status(dest: &mut BytesMut, status: &ClientStatus) -> EncodedRe
         ClientStatus::NeedInit => "NEEDINIT",
         ClientStatus::Ready => "READY",
         ClientStatus::HaveData => "HAVEDATA",
-        _ => unimplemented!(),
+        s @ (ClientStatus::Up | ClientStatus::Disconnected | ClientStatus::TimeOut) => {
+            return Err(protocol_error(format!("client status {s:?} can not be sent in i-PI protocol")));
+        }
     };
     encode_header(dest, s)?;
 
     Ok(())
 }
 
+/// Decode client status from message header in `src`, without consuming
+/// it. Unknown header is reported as protocol error.
 fn decode_client_status(src: &BytesMut) -> Result<ClientStatus, DecodeError> {
     let msg = try_decode_message_header(src, 12)?;
     let status = match msg.as_str() {
-        "NEEDINT" => ClientStatus::NeedInit,
+        "NEEDINIT" => ClientStatus::NeedInit,
         "READY" => ClientStatus::Ready,
         "HAVEDATA" => ClientStatus::HaveData,
-        _ => panic!("invalid message: {:?}", msg),
+        header_str => {
+            error!("invalid header: {:?}", header_str);
+            let msg = format!("invalid i-PI header from driver: {header_str:?}");
+            return Err(into_decode_error(protocol_error(msg)));
+        }
     };
     Ok(status)
 }
 
 #[test]
 fn test_ipi_status() {
-    let mut dest = BytesMut::new();
+    for s in [ClientStatus::NeedInit, ClientStatus::Ready, ClientStatus::HaveData] {
+        let mut dest = BytesMut::new();
+        encode_client_status(&mut dest, &s).unwrap();
+        let decoded = decode_client_status(&dest).unwrap();
+        assert_eq!(decoded, s);
+    }
 
-    let s = ClientStatus::Ready;
-    encode_client_status(&mut dest, &s);
-    let decoded = decode_client_status(&dest).unwrap();
-    assert_eq!(decoded, s);
+    // not a status in i-PI protocol
+    let mut dest = BytesMut::new();
+    assert!(encode_client_status(&mut dest, &ClientStatus::Up).is_err());
+    assert!(dest.is_empty());
+    // typo in header
+    let src = BytesMut::from(&b"NEEDINT     "[..]);
+    assert!(matches!(decode_client_status(&src), Err(DecodeError::IoError(_))));
 }
 // 50964fb6 ends here
 
@@ -130,26 +600,25 @@ fn test_ipi_status() {
 /// Init Message
 /// [12] [4]    [4(?)] [s...]
 /// INIT ibead  nbytes  ...
-fn decode_init(src: &mut BytesMut) -> Result<InitData, DecodeError> {
-    let msg = try_decode_message_header(src, 12)?;
-    assert_eq!(msg, "INIT");
-    let nbytes = try_decode_length_header_u32(src, 12 + 4)?;
-    let n_expected = 12 + 4 + 4 + nbytes;
+fn decode_init(src: &mut BytesMut, opts: &CodecOptions) -> Result<InitData, DecodeError> {
+    let isz = opts.int_size;
+    let nbytes = try_decode_length_header(src, 12 + isz, opts, opts.max_init, "size of INIT string")?;
+    try_decode_nbytes(src, 12 + 2 * isz + nbytes)?;
 
     src.advance(12);
-    let ibead = src.get_u32_le();
-    let nbytes = src.get_u32_le();
-    let init = src.copy_to_bytes(nbytes as usize);
-    let init = try_to_string(&init).map_err(|e| into_decode_error(e))?;
-    Ok(InitData::new(0, &init))
+    let ibead = opts.get_int(src);
+    let nbytes = opts.get_int(src);
+    let init = take_string(src, nbytes)?;
+    Ok(InitData::new(ibead, &init))
 }
 
-fn encode_init(dest: &mut BytesMut, init: InitData) -> EncodedResult {
+fn encode_init(dest: &mut BytesMut, init: InitData, opts: &CodecOptions) -> EncodedResult {
     encode_header(dest, "INIT")?;
 
     let InitData { ibead, nbytes, init } = init;
-    dest.put_u32_le(ibead as u32);
-    dest.put_u32_le(nbytes as u32);
+    dest.reserve(2 * opts.int_size + nbytes);
+    opts.put_int(dest, ibead);
+    opts.put_int(dest, nbytes);
     dest.put_slice(init.as_bytes());
 
     Ok(())
@@ -157,9 +626,10 @@ fn encode_init(dest: &mut BytesMut, init: InitData) -> EncodedResult {
 
 #[test]
 fn test_ipi_init() {
+    let opts = CodecOptions::default();
     let mut dest = BytesMut::new();
-    encode_init(&mut dest, InitData::new(0, "XX")).unwrap();
-    let x = decode_init(&mut dest).unwrap();
+    encode_init(&mut dest, InitData::new(0, "XX"), &opts).unwrap();
+    let x = decode_init(&mut dest, &opts).unwrap();
     assert_eq!(x.init, "XX");
 }
 // fd4f63b3 ends here
@@ -171,17 +641,21 @@ fn is_periodic(cell: [f64; 9]) -> bool {
     cell.iter().map(|x| x.abs()).sum::<f64>() > 1e-6
 }
 
-fn decode_posdata(src: &mut BytesMut) -> Result<Molecule, DecodeError> {
-    // 0. try to decode no advance, until we have enough data
-    let msg = try_decode_message_header(src, 12)?;
-    assert_eq!(msg, "POSDATA");
+fn decode_posdata(src: &mut BytesMut, opts: &CodecOptions) -> Result<Molecule, DecodeError> {
+    decode_posdata_into(src, opts, &mut Vec::new())
+}
 
+/// Decode POSDATA, using `coords` as buffer for coordinates, which can be
+/// reused across messages.
+fn decode_posdata_into(src: &mut BytesMut, opts: &CodecOptions, coords: &mut Vec<[f64; 3]>) -> Result<Molecule, DecodeError> {
+    // 0. try to decode no advance, until we have enough data
     let nbytes_cell = 9 * 8 * 2; // cell matrix and the inverse of cell matrix
     let nbytes_expected = 12 + nbytes_cell;
-    let natoms = try_decode_length_header_u32(&src, nbytes_expected)?;
+    let what = "number of atoms in POSDATA";
+    let natoms = try_decode_length_header(src, nbytes_expected, opts, opts.max_atoms, what)?;
 
     let nbytes_cart_coords = 3 * 8 * natoms;
-    let nbytes_expected = nbytes_expected + 4 + nbytes_cart_coords;
+    let nbytes_expected = nbytes_expected + opts.int_size + nbytes_cart_coords;
     try_decode_nbytes(src, nbytes_expected)?;
 
     // 1. start read message
@@ -189,7 +663,7 @@ fn decode_posdata(src: &mut BytesMut) -> Result<Molecule, DecodeError> {
     let mut cell = [0f64; 9];
     // nine floats for the cell vector matrix
     for i in 0..9 {
-        cell[i] = src.get_f64_le() * Bohr;
+        cell[i] = opts.get_f64(src) * Bohr;
     }
 
     // read inverse matrix of the cell
@@ -197,20 +671,14 @@ fn decode_posdata(src: &mut BytesMut) -> Result<Molecule, DecodeError> {
     // nine floats for the inverse matrix
     let mut _icell = [0f64; 9];
     for i in 0..9 {
-        _icell[i] = src.get_f64_le() * Bohr;
+        _icell[i] = opts.get_f64(src) * Bohr;
     }
 
-    let natoms = src.get_u32_le() as usize;
-    let mut coords = vec![[0f64; 3]; natoms];
-    for i in 0..natoms {
-        let x = src.get_f64_le() * Bohr;
-        let y = src.get_f64_le() * Bohr;
-        let z = src.get_f64_le() * Bohr;
-        coords[i] = [x, y, z];
-    }
+    let natoms = opts.get_int(src);
+    opts.get_vec3s_into(src, natoms, Bohr, coords);
 
     // FIXME: how to determinate element symbols?
-    let atoms: Vec<_> = coords.into_iter().map(|p| Atom::new("C", p)).collect();
+    let atoms: Vec<_> = coords.iter().map(|&p| Atom::new("C", p)).collect();
     let mut mol = Molecule::from_atoms(atoms);
 
     // NOTE: The cell is transposed when transfering
@@ -225,7 +693,9 @@ fn decode_posdata(src: &mut BytesMut) -> Result<Molecule, DecodeError> {
     Ok(mol)
 }
 
-fn encode_posdata(dest: &mut BytesMut, mol: &Molecule) -> EncodedResult {
+fn encode_posdata(dest: &mut BytesMut, mol: &Molecule, opts: &CodecOptions) -> EncodedResult {
+    let natoms = mol.natoms();
+    dest.reserve(HEADER_SIZE + 18 * 8 + opts.int_size + 24 * natoms);
     encode_header(dest, "POSDATA")?;
 
     let (cell, icell) = mol.get_lattice().as_ref().map_or_else(
@@ -236,20 +706,16 @@ fn encode_posdata(dest: &mut BytesMut, mol: &Molecule) -> EncodedResult {
 
     // I-PI assumes row major order for cell matrix
     for v in cell.transpose().as_slice() {
-        dest.put_f64_le(*v / Bohr);
+        opts.put_f64(dest, *v / Bohr);
     }
     // I-PI assumes row major order for cell matrix
     for v in icell.transpose().as_slice() {
-        dest.put_f64_le(*v * Bohr);
+        opts.put_f64(dest, *v * Bohr);
     }
 
     // write Cartesian coordinates
-    dest.put_u32_le(mol.natoms() as u32);
-    for [x, y, z] in mol.positions() {
-        dest.put_f64_le(x / Bohr);
-        dest.put_f64_le(y / Bohr);
-        dest.put_f64_le(z / Bohr);
-    }
+    opts.put_int(dest, natoms);
+    opts.put_vec3s(dest, mol.positions(), 1.0 / Bohr);
 
     Ok(())
 }
@@ -258,10 +724,11 @@ fn encode_posdata(dest: &mut BytesMut, mol: &Molecule) -> EncodedResult {
 fn test_decode_posdata() {
     use approx::*;
 
+    let opts = CodecOptions::default();
     let mol1 = Molecule::from_file("tests/files/quinone.cif").unwrap();
     let mut dest = BytesMut::new();
-    encode_posdata(&mut dest, &mol1);
-    let mol2 = decode_posdata(&mut dest).unwrap();
+    encode_posdata(&mut dest, &mol1, &opts);
+    let mol2 = decode_posdata(&mut dest, &opts).unwrap();
     assert_eq!(mol1.natoms(), mol2.natoms());
     let [va1, vb1, vc1] = mol1.get_lattice().unwrap().vectors();
     let [va2, vb2, vc2] = mol2.get_lattice().unwrap().vectors();
@@ -281,60 +748,53 @@ fn test_decode_posdata() {
 // a2dca708 ends here
 
 // [[file:../ipi.note::848513f5][848513f5]]
-fn encode_client_computed(dst: &mut BytesMut, computed: &Computed) -> EncodedResult {
-    let s = format_header("FORCEREADY");
-    dst.put_slice(s.as_bytes());
-    dst.put_f64_le(computed.energy / Hartree);
+fn encode_client_computed(dst: &mut BytesMut, computed: &Computed, opts: &CodecOptions) -> EncodedResult {
     let n = computed.forces.len();
-    dst.put_u32_le(n as u32);
-    let f = Bohr / Hartree;
-    for i in 0..n {
-        dst.put_f64_le(computed.forces[i][0] * f);
-        dst.put_f64_le(computed.forces[i][1] * f);
-        dst.put_f64_le(computed.forces[i][2] * f);
-    }
+    dst.reserve(HEADER_SIZE + 8 + 2 * opts.int_size + 24 * n + 9 * 8 + computed.extra.len());
+    encode_header(dst, "FORCEREADY")?;
+    opts.put_f64(dst, computed.energy / Hartree);
+    opts.put_int(dst, n);
+    opts.put_vec3s(dst, computed.forces.iter().copied(), Bohr / Hartree);
     for i in 0..9 {
-        dst.put_f64_le(computed.virial[i] * Hartree);
+        opts.put_f64(dst, computed.virial[i] / Hartree);
     }
     let n = computed.extra.len();
-    dst.put_u32_le(n as u32);
+    opts.put_int(dst, n);
     dst.put_slice(computed.extra.as_bytes());
 
     Ok(())
 }
 
-fn decode_client_computed(src: &mut BytesMut) -> Result<Computed, DecodeError> {
-    let nheader = 12;
-    let msg = try_decode_message_header(src, nheader)?;
-    assert_eq!(msg, "FORCEREADY");
+/// Decode FORCEREADY message, which should be for `expected` number of
+/// atoms if known.
+fn decode_client_computed(src: &mut BytesMut, opts: &CodecOptions, expected: Option<usize>) -> Result<Computed, DecodeError> {
+    let nheader = HEADER_SIZE;
 
     // try to read natoms
     let nenergy = 8;
-    let natoms = try_decode_length_header_u32(src, nheader + nenergy)?;
+    let what = "number of atoms in FORCEREADY";
+    let natoms = try_decode_length_header(src, nheader + nenergy, opts, opts.max_atoms, what)?;
+    check_natoms(src, nheader + nenergy, opts, natoms, expected, what)?;
     let nforces = 3 * natoms * 8;
     let nviral = 9 * 8; // nine float numbers (f64)
-    let nbytes_expected = 12 + 8 + 4 + nforces + nviral;
+    let nbytes_expected = 12 + 8 + opts.int_size + nforces + nviral;
     // try to read extra data
-    let nextra = try_decode_length_header_u32(src, nbytes_expected)?;
+    let what = "size of extra data in FORCEREADY";
+    let nextra = try_decode_length_header(src, nbytes_expected, opts, opts.max_extra, what)?;
+    try_decode_nbytes(src, nbytes_expected + opts.int_size + nextra)?;
 
     // start reading message now
     src.advance(nheader);
-    let energy = src.get_f64_le() * Hartree;
-    let natoms = src.get_u32_le() as usize;
-    let mut forces = vec![[0.0; 3]; natoms];
-    for i in 0..natoms {
-        for j in 0..3 {
-            forces[i][j] = src.get_f64_le() * Hartree / Bohr;
-        }
-    }
+    let energy = opts.get_f64(src) * Hartree;
+    let natoms = opts.get_int(src);
+    let forces = opts.get_vec3s(src, natoms, Hartree / Bohr);
     let mut virial = [0.0; 9];
     for i in 0..9 {
-        virial[i] = src.get_f64_le() * Hartree;
+        virial[i] = opts.get_f64(src) * Hartree;
     }
     // extra field JSON string
-    let nextra = src.get_u32_le();
-    let bytes = src.copy_to_bytes(nextra as usize);
-    let extra = try_to_string(&bytes).map_err(|e| into_decode_error(e))?;
+    let nextra = opts.get_int(src);
+    let extra = take_string(src, nextra)?;
 
     let computed = Computed {
         energy,
@@ -351,7 +811,25 @@ fn decode_client_computed(src: &mut BytesMut) -> Result<Computed, DecodeError> {
 use tokio_util::codec::{Decoder, Encoder};
 
 /// Client side encoding/decoding
-pub struct ClientCodec;
+#[derive(Debug, Clone, Default)]
+pub struct ClientCodec {
+    opts: CodecOptions,
+    /// The number of atoms in POSDATA sent to client, for checking
+    /// FORCEREADY from it
+    natoms: Option<usize>,
+}
+
+impl ClientCodec {
+    /// Create codec for messages in binary layout specified in `opts`.
+    pub fn new(opts: CodecOptions) -> Self {
+        Self { opts, natoms: None }
+    }
+
+    /// Expect FORCEREADY for `natoms` atoms, as sent in POSDATA.
+    pub(crate) fn expect_natoms(&mut self, natoms: usize) {
+        self.natoms = Some(natoms);
+    }
+}
 
 impl Decoder for ClientCodec {
     type Item = ClientMessage;
@@ -359,27 +837,18 @@ impl Decoder for ClientCodec {
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         match try_decode_message_header(src, 12) {
-            Ok(header_str) => match header_str.as_str() {
-                "NEEDINIT" => {
-                    src.advance(12);
-                    Ok(Some(ClientMessage::Status(ClientStatus::NeedInit)))
-                }
-                "READY" => {
-                    src.advance(12);
-                    Ok(Some(ClientMessage::Status(ClientStatus::Ready)))
-                }
-                "HAVEDATA" => {
-                    src.advance(12);
-                    Ok(Some(ClientMessage::Status(ClientStatus::HaveData)))
-                }
-                "FORCEREADY" => match decode_client_computed(src) {
+            Ok(header) => match header.as_str() {
+                "FORCEREADY" => match decode_client_computed(src, &self.opts, self.natoms) {
                     Err(e) => fix_decode_err(e),
                     Ok(computed) => Ok(Some(ClientMessage::ForceReady(computed))),
                 },
-                _ => {
-                    error!("invalid header: {:?}", header_str);
-                    todo!();
-                }
+                _ => match decode_client_status(src) {
+                    Err(e) => fix_decode_err(e),
+                    Ok(status) => {
+                        src.advance(12);
+                        Ok(Some(ClientMessage::Status(status)))
+                    }
+                },
             },
             Err(e) => fix_decode_err(e),
         }
@@ -392,7 +861,7 @@ impl Encoder<ClientMessage> for ClientCodec {
     fn encode(&mut self, item: ClientMessage, dest: &mut BytesMut) -> Result<(), Self::Error> {
         match item {
             ClientMessage::Status(status) => encode_client_status(dest, &status),
-            ClientMessage::ForceReady(computed) => encode_client_computed(dest, &computed),
+            ClientMessage::ForceReady(computed) => encode_client_computed(dest, &computed, &self.opts),
         }
     }
 }
@@ -400,7 +869,19 @@ impl Encoder<ClientMessage> for ClientCodec {
 
 // [[file:../ipi.note::c2814be6][c2814be6]]
 /// Server side encoding/decoding
-pub struct ServerCodec;
+#[derive(Debug, Clone, Default)]
+pub struct ServerCodec {
+    opts: CodecOptions,
+    /// Buffer for decoding coordinates in POSDATA, reused across messages
+    coords: Vec<[f64; 3]>,
+}
+
+impl ServerCodec {
+    /// Create codec for messages in binary layout specified in `opts`.
+    pub fn new(opts: CodecOptions) -> Self {
+        Self { opts, coords: vec![] }
+    }
+}
 
 impl Decoder for ServerCodec {
     type Item = ServerMessage;
@@ -408,7 +889,7 @@ impl Decoder for ServerCodec {
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         match try_decode_message_header(src, 12) {
-            Ok(header_str) => match header_str.as_str() {
+            Ok(header) => match header.as_str() {
                 "STATUS" => {
                     src.advance(12);
                     Ok(Some(ServerMessage::Status))
@@ -421,17 +902,17 @@ impl Decoder for ServerCodec {
                     src.advance(12);
                     Ok(Some(ServerMessage::Exit))
                 }
-                "INIT" => match decode_init(src) {
+                "INIT" => match decode_init(src, &self.opts) {
                     Err(e) => fix_decode_err(e),
                     Ok(init_data) => Ok(Some(ServerMessage::Init(init_data))),
                 },
-                "POSDATA" => match decode_posdata(src) {
+                "POSDATA" => match decode_posdata_into(src, &self.opts, &mut self.coords) {
                     Err(e) => fix_decode_err(e),
                     Ok(mol) => Ok(Some(ServerMessage::PosData(mol))),
                 },
-                _ => {
+                header_str => {
                     error!("invalid header: {}", header_str);
-                    todo!();
+                    Err(protocol_error(format!("invalid i-PI header from server: {header_str:?}")))
                 }
             },
             Err(e) => fix_decode_err(e),
@@ -447,10 +928,65 @@ impl Encoder<ServerMessage> for ServerCodec {
             ServerMessage::Status => encode_header(dest, "STATUS"),
             ServerMessage::GetForce => encode_header(dest, "GETFORCE"),
             ServerMessage::Exit => encode_header(dest, "EXIT"),
-            ServerMessage::Init(data) => encode_init(dest, data),
-            ServerMessage::PosData(mol) => encode_posdata(dest, &mol),
+            ServerMessage::Init(data) => encode_init(dest, data, &self.opts),
+            ServerMessage::PosData(mol) => encode_posdata(dest, &mol, &self.opts),
 
         }
     }
 }
 // c2814be6 ends here
+
+// [[file:../ipi.note::8e4f1a2c][8e4f1a2c]]
+#[test]
+fn test_ase_reference_frames() {
+    use approx::*;
+
+    // NOTE: the reference frames are synthesized by
+    // tests/files/ase-session.py following ASE's framing, not captured from
+    // ASE
+
+    // messages as sent by ASE's SocketIOCalculator
+    let data = std::fs::read("tests/files/ase-server.dat").unwrap();
+    let mut src = BytesMut::from(&data[..]);
+    let mut msgs = vec![];
+    while let Some(msg) = ServerCodec::default().decode(&mut src).unwrap() {
+        msgs.push(msg);
+    }
+    assert!(src.is_empty());
+    assert_eq!(msgs.len(), 13);
+    match &msgs[1] {
+        ServerMessage::Init(init) => assert_eq!(init.init, ""),
+        x => panic!("unexpected message: {x:?}"),
+    }
+    match &msgs[3] {
+        ServerMessage::PosData(mol) => {
+            assert_eq!(mol.natoms(), 3);
+            let [a, b, c] = mol.get_lattice().unwrap().lengths();
+            assert_relative_eq!(a, 10.0, epsilon = 1e-6);
+            assert_relative_eq!(b, 10.0, epsilon = 1e-6);
+            assert_relative_eq!(c, 10.0, epsilon = 1e-6);
+            let positions: Vec<_> = mol.positions().collect();
+            assert_relative_eq!(positions[1][1], 5.7572, epsilon = 1e-6);
+        }
+        x => panic!("unexpected message: {x:?}"),
+    }
+
+    // messages as sent by ASE's SocketClient
+    let data = std::fs::read("tests/files/ase-client.dat").unwrap();
+    let mut src = BytesMut::from(&data[..]);
+    let mut msgs = vec![];
+    while let Some(msg) = ClientCodec::default().decode(&mut src).unwrap() {
+        msgs.push(msg);
+    }
+    assert!(src.is_empty());
+    assert_eq!(msgs.len(), 8);
+    match &msgs[3] {
+        ClientMessage::ForceReady(computed) => {
+            assert_relative_eq!(computed.energy, -14.2234, epsilon = 1e-6);
+            assert_relative_eq!(computed.forces[1][1], 0.2311, epsilon = 1e-6);
+            assert_eq!(computed.extra, "");
+        }
+        x => panic!("unexpected message: {x:?}"),
+    }
+}
+// 8e4f1a2c ends here
@@ -0,0 +1,478 @@
+// [[file:../ipi.note::0d73abc6][0d73abc6]]
+use super::*;
+use socket::Socket;
+use task::{Task, TaskSender};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+// 0d73abc6 ends here
+
+// [[file:../ipi.note::1cfc6b23][1cfc6b23]]
+/// The i-PI server for one driver in composite model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DriverConfig {
+    /// The host name for i-PI server to listen on, or the name of unix
+    /// domain socket
+    pub host: String,
+    /// The port for i-PI server to listen on
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Use unix domain socket instead of internet socket
+    #[serde(default)]
+    pub unix: bool,
+    /// Spawn this shell command as driver talking i-PI over stdin/stdout,
+    /// instead of listening on socket
+    #[serde(default)]
+    pub pipe: Option<String>,
+    /// Encrypt connections on internet socket with TLS, requiring drivers
+    /// to present certificates
+    #[serde(default)]
+    pub tls: Option<tls::TlsOptions>,
+    /// Poll idle drivers with STATUS in this interval in seconds. Drivers
+    /// not answering in time are declared dead and removed.
+    #[serde(default)]
+    pub heartbeat: Option<f64>,
+    /// The time in seconds to wait for drivers answering heartbeat STATUS.
+    /// Default to 10 seconds.
+    #[serde(default)]
+    pub heartbeat_timeout: Option<f64>,
+    /// TCP keepalive settings for drivers on internet socket
+    #[serde(default)]
+    pub keepalive: socket::KeepaliveOptions,
+    /// The binary layout of i-PI messages, for drivers sending big-endian
+    /// numbers or 8-byte integers
+    #[serde(default)]
+    pub codec: codec::CodecOptions,
+}
+
+fn default_port() -> u16 {
+    12345
+}
+
+impl Default for DriverConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".into(),
+            port: default_port(),
+            unix: false,
+            pipe: None,
+            tls: None,
+            heartbeat: None,
+            heartbeat_timeout: None,
+            keepalive: Default::default(),
+            codec: codec::CodecOptions::default(),
+        }
+    }
+}
+
+impl DriverConfig {
+    /// Return the i-PI address in a human readable way.
+    pub(crate) fn describe(&self) -> String {
+        match &self.pipe {
+            Some(command) => format!("pipe:{command}"),
+            None if self.tls.is_some() => format!("tls:{}:{}", self.host, self.port),
+            None => socket::describe_address(&self.host, self.port, self.unix),
+        }
+    }
+
+    /// Return the heartbeat for polling idle drivers, if enabled.
+    pub(crate) fn heartbeat(&self) -> Option<ipi::Heartbeat> {
+        let interval = self.heartbeat?;
+        Some(ipi::Heartbeat {
+            interval: std::time::Duration::from_secs_f64(interval),
+            timeout: std::time::Duration::from_secs_f64(self.heartbeat_timeout.unwrap_or(10.0)),
+        })
+    }
+
+    /// Listen for driver connections, or spawn `copies` of driver for pipe.
+    pub(crate) async fn listen(&self, copies: usize) -> Result<socket::IpiListener> {
+        self.codec.check()?;
+        let listener = match &self.pipe {
+            Some(command) => Socket::spawn(command, copies),
+            None => match &self.tls {
+                Some(tls) => {
+                    ensure!(!self.unix, "TLS is only for internet socket");
+                    Socket::bind_tls(&self.host, self.port, tls, self.keepalive).await?
+                }
+                None => Socket::bind(&self.host, self.port, self.unix).await?,
+            },
+        };
+        Ok(listener.with_codec(self.codec).with_keepalive(self.keepalive))
+    }
+}
+
+/// One term contributing to the composite energy and forces
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Term {
+    /// The name of driver for computing this term
+    pub driver: String,
+    /// The coefficient for combining this term
+    #[serde(default = "default_coeff")]
+    pub coeff: f64,
+    /// Atom numbers (1-based) of the subsystem. The full system will be
+    /// computed if not set.
+    #[serde(default)]
+    pub region: Option<Vec<usize>>,
+}
+
+fn default_coeff() -> f64 {
+    1.0
+}
+
+fn default_link_ratio() -> f64 {
+    0.709
+}
+
+/// Configuration of composite model like:
+///
+/// ```json
+/// {
+///   "drivers": {
+///     "high": {"host": "high", "unix": true},
+///     "low": {"host": "low", "unix": true},
+///     "low-region": {"host": "low-region", "unix": true}
+///   },
+///   "terms": [
+///     {"driver": "low"},
+///     {"driver": "high", "region": [1, 2, 3]},
+///     {"driver": "low-region", "coeff": -1.0, "region": [1, 2, 3]}
+///   ]
+/// }
+/// ```
+///
+/// which gives E = E_high(region) + E_low(full) − E_low(region) in ONIOM
+/// scheme. A linear mix of two drivers or Δ-learning can be defined in the
+/// same way.
+///
+/// Each term requires its own driver, since i-PI codes can not change the
+/// number of atoms on one connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompositeConfig {
+    /// i-PI servers for drivers indexed by name
+    pub drivers: HashMap<String, DriverConfig>,
+    /// Terms to be combined
+    pub terms: Vec<Term>,
+    /// Bonds (pairs of 1-based atom numbers) cut by region boundary, which
+    /// will be capped with link hydrogen atoms. The first atom is inside
+    /// region. Will be detected from connectivity if not set.
+    #[serde(default)]
+    pub links: Option<Vec<[usize; 2]>>,
+    /// The ratio for placing link atom along the cut bond
+    #[serde(default = "default_link_ratio")]
+    pub link_ratio: f64,
+}
+
+impl CompositeConfig {
+    /// Read composite model configuration from a JSON file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let s = gut::fs::read_file(path)?;
+        let config: Self = serde_json::from_str(&s).with_context(|| format!("invalid composite config: {path:?}"))?;
+        config.check().with_context(|| format!("invalid composite config: {path:?}"))?;
+        Ok(config)
+    }
+
+    /// Check that all terms refer to defined drivers, no driver is shared
+    /// by terms, and drivers on internet socket listen on distinct ports.
+    fn check(&self) -> Result<()> {
+        let mut used = std::collections::HashSet::new();
+        for t in self.terms.iter() {
+            ensure!(self.drivers.contains_key(&t.driver), "undefined driver in term: {}", t.driver);
+            ensure!(used.insert(&t.driver), "driver {} is used by more than one term", t.driver);
+        }
+        let mut ports = HashMap::new();
+        for (name, d) in self.drivers.iter().filter(|(_, d)| d.pipe.is_none() && !d.unix) {
+            ensure!(d.port != 0, "driver {name} listens on port 0, which can not be found by external code");
+            if let Some(other) = ports.insert(d.port, name) {
+                bail!("drivers {other} and {name} listen on the same port {}", d.port);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_composite_config() {
+    let config: CompositeConfig = serde_json::from_str(
+        r#"{
+            "drivers": {"high": {"host": "high"}, "low": {"host": "low"}},
+            "terms": [{"driver": "low"}, {"driver": "high", "region": [1]}, {"driver": "low", "region": [1]}]
+        }"#,
+    )
+    .unwrap();
+    assert!(config.check().is_err());
+    let config: CompositeConfig = serde_json::from_str(
+        r#"{"drivers": {"high": {"host": "high"}}, "terms": [{"driver": "low"}]}"#,
+    )
+    .unwrap();
+    assert!(config.check().is_err());
+
+    // the default port is the same as in Default
+    let config: CompositeConfig = serde_json::from_str(
+        r#"{"drivers": {"high": {"host": "localhost"}, "low": {"host": "low", "unix": true}}, "terms": [{"driver": "low"}, {"driver": "high", "region": [1]}]}"#,
+    )
+    .unwrap();
+    assert_eq!(config.drivers["high"].port, DriverConfig::default().port);
+    assert!(config.check().is_ok());
+    // ports must be distinct and non-zero
+    let config: CompositeConfig = serde_json::from_str(
+        r#"{"drivers": {"high": {"host": "localhost"}, "low": {"host": "localhost"}}, "terms": [{"driver": "low"}, {"driver": "high", "region": [1]}]}"#,
+    )
+    .unwrap();
+    assert!(config.check().is_err());
+    let config: CompositeConfig = serde_json::from_str(
+        r#"{"drivers": {"high": {"host": "localhost", "port": 0}}, "terms": [{"driver": "high"}]}"#,
+    )
+    .unwrap();
+    assert!(config.check().is_err());
+}
+// 1cfc6b23 ends here
+
+// [[file:../ipi.note::d5a8d2dc][d5a8d2dc]]
+/// A subsystem cut from the full system, with link atoms appended.
+struct Subsystem {
+    mol: Molecule,
+    /// Atom indices (0-based) in the full system for real atoms
+    atoms: Vec<usize>,
+    /// Pairs of atom indices in the full system for link atoms
+    links: Vec<[usize; 2]>,
+}
+
+/// Return bonds between `region` and the rest atoms in `mol`, in pairs of
+/// 1-based atom numbers.
+fn detect_links(mol: &Molecule, region: &[usize]) -> Vec<[usize; 2]> {
+    let mut mol = mol.clone();
+    mol.rebond();
+    mol.bonds()
+        .filter_map(|(i, j, _)| match (region.contains(&i), region.contains(&j)) {
+            (true, false) => Some([i, j]),
+            (false, true) => Some([j, i]),
+            _ => None,
+        })
+        .collect()
+}
+
+impl Subsystem {
+    /// Cut `region` from `mol` as a cluster, with link atoms placed along
+    /// `links` at `ratio`. For periodic `mol`, atoms in region are unwrapped
+    /// around its first atom in minimum image, so the cluster is not broken
+    /// by cell boundary.
+    fn new(mol: &Molecule, region: &[usize], links: &[[usize; 2]], ratio: f64) -> Result<Self> {
+        let natoms = mol.natoms();
+        let mut positions: Vec<_> = mol.positions().collect();
+        let symbols: Vec<_> = mol.symbols().collect();
+        for &i in region {
+            ensure!(i >= 1 && i <= natoms, "invalid atom number in region: {i}");
+        }
+        let cell = cell_vectors(mol);
+        let unwrap = |origin: [f64; 3], p: [f64; 3]| {
+            let d = minimum_image(cell.as_ref(), std::array::from_fn(|k| p[k] - origin[k]));
+            std::array::from_fn(|k| origin[k] + d[k])
+        };
+        if let Some(&first) = region.first() {
+            let origin = positions[first - 1];
+            for &i in region {
+                positions[i - 1] = unwrap(origin, positions[i - 1]);
+            }
+        }
+
+        let mut atoms_sub = vec![];
+        for &i in region {
+            atoms_sub.push(Atom::new(symbols[i - 1], positions[i - 1]));
+        }
+        let links: Vec<_> = links
+            .iter()
+            .filter(|[q, _]| region.contains(q))
+            .map(|[q, m]| [q - 1, m - 1])
+            .collect();
+        for &[q, m] in links.iter() {
+            let pq = positions[q];
+            let pm: [f64; 3] = unwrap(pq, positions[m]);
+            let p: [f64; 3] = std::array::from_fn(|k| pq[k] + ratio * (pm[k] - pq[k]));
+            atoms_sub.push(Atom::new("H", p));
+        }
+
+        let mut sub = Molecule::from_atoms(atoms_sub);
+        sub.set_title(mol.title());
+        Ok(Self {
+            mol: sub,
+            atoms: region.iter().map(|i| i - 1).collect(),
+            links,
+        })
+    }
+
+    /// Add forces of subsystem scaled by `coeff` into `forces` of full
+    /// system. Forces on link atoms are distributed onto the two atoms of
+    /// the cut bond.
+    fn add_forces(&self, forces_sub: &[[f64; 3]], coeff: f64, ratio: f64, forces: &mut [[f64; 3]]) {
+        let n = self.atoms.len();
+        for (&i, f) in self.atoms.iter().zip(forces_sub) {
+            for k in 0..3 {
+                forces[i][k] += coeff * f[k];
+            }
+        }
+        for (&[q, m], f) in self.links.iter().zip(&forces_sub[n..]) {
+            for k in 0..3 {
+                forces[q][k] += coeff * (1.0 - ratio) * f[k];
+                forces[m][k] += coeff * ratio * f[k];
+            }
+        }
+    }
+}
+
+#[test]
+fn test_subsystem() -> Result<()> {
+    use approx::*;
+
+    // a chain of three carbon atoms, with region cut at the second bond
+    let atoms = [[0.0, 0.0, 0.0], [1.5, 0.0, 0.0], [3.0, 0.0, 0.0]].map(|p| Atom::new("C", p));
+    let mol = Molecule::from_atoms(atoms);
+    let region = [1, 2];
+    let links = detect_links(&mol, &region);
+    assert_eq!(links, vec![[2, 3]]);
+
+    let ratio = 0.709;
+    let sub = Subsystem::new(&mol, &region, &links, ratio)?;
+    assert_eq!(sub.mol.natoms(), 3);
+    assert!(sub.mol.symbols().eq(["C", "C", "H"]));
+    let h = sub.mol.positions().nth(2).unwrap();
+    assert_relative_eq!(h[0], 1.5 + ratio * 1.5, epsilon = 1e-8);
+    assert!(Subsystem::new(&mol, &[4], &links, ratio).is_err());
+
+    // forces on link atom are distributed onto the cut bond, and the total
+    // force is conserved
+    let forces_sub = [[0.1, 0.2, 0.3], [0.4, 0.0, -0.1], [1.0, -0.5, 0.2]];
+    let mut forces = vec![[0.0; 3]; 3];
+    sub.add_forces(&forces_sub, -1.0, ratio, &mut forces);
+    assert_eq!(forces[0], [-0.1, -0.2, -0.3]);
+    for k in 0..3 {
+        assert_relative_eq!(forces[1][k], -(forces_sub[1][k] + (1.0 - ratio) * forces_sub[2][k]), epsilon = 1e-12);
+        assert_relative_eq!(forces[2][k], -ratio * forces_sub[2][k], epsilon = 1e-12);
+        let total: f64 = forces_sub.iter().map(|f| f[k]).sum();
+        assert_relative_eq!(forces.iter().map(|f| f[k]).sum::<f64>(), -total, epsilon = 1e-12);
+    }
+
+    // the same chain across the boundary of periodic cell
+    let atoms = [[9.0, 0.0, 0.0], [0.5, 0.0, 0.0], [2.0, 0.0, 0.0]].map(|p| Atom::new("C", p));
+    let mut mol = Molecule::from_atoms(atoms);
+    mol.set_lattice(Lattice::new([[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [0.0, 0.0, 10.0]]));
+    let sub = Subsystem::new(&mol, &region, &[[2, 3]], ratio)?;
+    let ps: Vec<_> = sub.mol.positions().collect();
+    assert_relative_eq!(ps[1][0] - ps[0][0], 1.5, epsilon = 1e-8);
+    assert_relative_eq!(ps[2][0] - ps[1][0], ratio * 1.5, epsilon = 1e-8);
+
+    Ok(())
+}
+// d5a8d2dc ends here
+
+// [[file:../ipi.note::a4c1e7b0][a4c1e7b0]]
+/// A model combining energies and forces computed by several drivers
+pub struct CompositeModel {
+    config: CompositeConfig,
+    drivers: HashMap<String, TaskSender>,
+    /// Links detected from connectivity of the first structure, indexed by
+    /// term
+    links: std::sync::Mutex<HashMap<usize, Vec<[usize; 2]>>>,
+}
+
+impl CompositeModel {
+    /// Start i-PI servers for all drivers in `config`, waiting for external
+    /// codes to connect.
+    pub async fn start(config: CompositeConfig) -> Result<Self> {
+        let mut drivers = HashMap::new();
+        for (name, d) in config.drivers.iter() {
+            let ipi_server = d.listen(1).await?;
+            let (task_rx, task_tx) = Task::new().split();
+            let driver = name.clone();
+            let heartbeat = d.heartbeat();
+            tokio::spawn(async move {
+                if let Err(err) = ipi_server.serve_channel_with_heartbeat(task_rx, heartbeat).await {
+                    error!("driver {driver}: {err:?}");
+                }
+            });
+            drivers.insert(name.clone(), task_tx);
+        }
+
+        Ok(Self {
+            config,
+            drivers,
+            links: Default::default(),
+        })
+    }
+
+    /// Return links cut by `region` of term `iterm`. Links not defined in
+    /// config are detected from the first structure only, since rebonding
+    /// large systems in each step is costly.
+    fn links(&self, iterm: usize, mol: &Molecule, region: &[usize]) -> Vec<[usize; 2]> {
+        match &self.config.links {
+            Some(links) => links.clone(),
+            None => {
+                let mut cached = self.links.lock().unwrap();
+                cached.entry(iterm).or_insert_with(|| detect_links(mol, region)).clone()
+            }
+        }
+    }
+
+    /// Compute all terms for `mol` concurrently, and combine them.
+    pub async fn compute(&self, mol: &Molecule) -> Result<Computed> {
+        let natoms = mol.natoms();
+        let ratio = self.config.link_ratio;
+
+        let mut jobs = vec![];
+        for (iterm, t) in self.config.terms.iter().enumerate() {
+            let sub = match &t.region {
+                Some(region) => {
+                    let links = self.links(iterm, mol, region);
+                    Some(Subsystem::new(mol, region, &links, ratio)?)
+                }
+                None => None,
+            };
+            let task = &self.drivers[&t.driver];
+            let mol = sub.as_ref().map_or_else(|| mol.clone(), |s| s.mol.clone());
+            jobs.push(async move {
+                task.remote_compute(mol)
+                    .await
+                    .with_context(|| format!("failed to compute term using driver {}", t.driver))
+                    .map(|computed| (t, sub, computed))
+            });
+        }
+        let computed_terms = futures::future::try_join_all(jobs).await?;
+
+        let mut energy = 0.0;
+        let mut forces = vec![[0.0; 3]; natoms];
+        let mut virial = [0.0; 9];
+        let mut energies = vec![];
+        for (t, sub, c) in computed_terms {
+            energy += t.coeff * c.energy;
+            energies.push(c.energy);
+            match sub {
+                Some(sub) => {
+                    ensure!(c.forces.len() == sub.mol.natoms(), "invalid forces from driver {}", t.driver);
+                    sub.add_forces(&c.forces, t.coeff, ratio, &mut forces);
+                }
+                None => {
+                    ensure!(c.forces.len() == natoms, "invalid forces from driver {}", t.driver);
+                    for (f, fc) in forces.iter_mut().zip(&c.forces) {
+                        for k in 0..3 {
+                            f[k] += t.coeff * fc[k];
+                        }
+                    }
+                    // NOTE: virial is only meaningful for the full system
+                    for k in 0..9 {
+                        virial[k] += t.coeff * c.virial[k];
+                    }
+                }
+            }
+        }
+
+        let mut computed = Computed {
+            energy,
+            forces,
+            virial,
+            extra: String::new(),
+        };
+        computed.insert_extra("term_energies", energies.into());
+
+        Ok(computed)
+    }
+}
+// a4c1e7b0 ends here
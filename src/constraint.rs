@@ -0,0 +1,337 @@
+// [[file:../ipi.note::7c2a9e15][7c2a9e15]]
+use super::*;
+
+use serde::{Deserialize, Serialize};
+// 7c2a9e15 ends here
+
+// [[file:../ipi.note::b84f0d63][b84f0d63]]
+/// Cartesian components fixed for selected atoms.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FixedComponents {
+    /// Atoms in selection syntax, e.g. "1-4,7"
+    pub atoms: String,
+    /// Fixed components, e.g. "z" or "xy"
+    pub components: String,
+}
+
+/// Constraints on atoms, which are imposed on computed forces before they
+/// are handed to optimizers or MD, since POSDATA can not express them.
+/// Atom numbers are 1-based as in gchemol.
+///
+/// ```json
+/// {
+///   "from_molecule": true,
+///   "fixed": "1-16",
+///   "components": [{"atoms": "17-32", "components": "z"}],
+///   "rigid": ["33-35", "36-38"]
+/// }
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Constraints {
+    /// Freeze atoms (or their components) marked in the input molecule
+    #[serde(default)]
+    pub from_molecule: bool,
+    /// Atoms in selection syntax to be frozen completely
+    #[serde(default)]
+    pub fixed: Option<String>,
+    /// Cartesian components to be fixed
+    #[serde(default)]
+    pub components: Vec<FixedComponents>,
+    /// Fragments in selection syntax moving as rigid bodies
+    #[serde(default)]
+    pub rigid: Vec<String>,
+}
+
+/// Parse atom selection like "1-4,7,10-12" into 1-based atom numbers.
+pub fn parse_selection(s: &str) -> Result<Vec<usize>> {
+    let mut atoms = vec![];
+    for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let parse = |x: &str| -> Result<usize> {
+            let i: usize = x.trim().parse().with_context(|| format!("invalid atom number in selection: {x:?}"))?;
+            ensure!(i >= 1, "atom number in selection starts from 1: {s:?}");
+            Ok(i)
+        };
+        match part.split_once('-') {
+            Some((a, b)) => {
+                let (a, b) = (parse(a)?, parse(b)?);
+                ensure!(a <= b, "invalid atom range in selection: {part:?}");
+                atoms.extend(a..=b);
+            }
+            None => atoms.push(parse(part)?),
+        }
+    }
+    ensure!(!atoms.is_empty(), "empty atom selection: {s:?}");
+    Ok(atoms)
+}
+
+/// Parse Cartesian components like "xz" into flags.
+fn parse_components(s: &str) -> Result<[bool; 3]> {
+    let mut fixed = [false; 3];
+    for c in s.chars().filter(|c| !c.is_whitespace()) {
+        match c.to_ascii_lowercase() {
+            'x' => fixed[0] = true,
+            'y' => fixed[1] = true,
+            'z' => fixed[2] = true,
+            _ => bail!("invalid Cartesian component: {c:?}"),
+        }
+    }
+    Ok(fixed)
+}
+// b84f0d63 ends here
+
+// [[file:../ipi.note::e31d7a48][e31d7a48]]
+type Vector3 = [f64; 3];
+
+fn cross(a: Vector3, b: Vector3) -> Vector3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+/// Solve `m x = b` for symmetric positive semidefinite `m`. The null space
+/// (e.g. rotation around the axis of linear fragment) is regularized away.
+fn solve3(m: [[f64; 3]; 3], b: Vector3) -> Vector3 {
+    let eps = 1e-10 * (1.0 + m[0][0] + m[1][1] + m[2][2]);
+    let mut m = m;
+    for k in 0..3 {
+        m[k][k] += eps;
+    }
+    // inverse by adjugate
+    let c = |i: usize, j: usize| {
+        let (i1, i2) = ((i + 1) % 3, (i + 2) % 3);
+        let (j1, j2) = ((j + 1) % 3, (j + 2) % 3);
+        m[i1][j1] * m[i2][j2] - m[i1][j2] * m[i2][j1]
+    };
+    let det = m[0][0] * c(0, 0) + m[0][1] * c(0, 1) + m[0][2] * c(0, 2);
+    std::array::from_fn(|i| (0..3).map(|j| c(j, i) * b[j]).sum::<f64>() / det)
+}
+
+/// Project `forces` on atoms at `positions` with `masses` onto rigid body
+/// motions, i.e. the translation and rotation around the center of mass of
+/// the fragment: `f_i = m_i (F / M + α × r_i)`, where `α` is the angular
+/// acceleration from total torque and the inertia tensor. So the atoms
+/// accelerate as one rigid body in MD, keeping total force and torque.
+///
+/// NOTE: the fragment should not be broken by periodic boundary.
+fn project_rigid(positions: &[Vector3], masses: &[f64], forces: &mut [Vector3]) {
+    let m: f64 = masses.iter().sum();
+    let c: Vector3 = std::array::from_fn(|k| positions.iter().zip(masses).map(|(p, mi)| mi * p[k]).sum::<f64>() / m);
+    let rs: Vec<Vector3> = positions.iter().map(|p| std::array::from_fn(|k| p[k] - c[k])).collect();
+
+    // linear acceleration, total torque and inertia tensor
+    let a: Vector3 = std::array::from_fn(|k| forces.iter().map(|f| f[k]).sum::<f64>() / m);
+    let mut torque = [0.0; 3];
+    let mut inertia = [[0.0; 3]; 3];
+    for ((r, fi), mi) in rs.iter().zip(forces.iter()).zip(masses) {
+        let t = cross(*r, *fi);
+        let r2: f64 = r.iter().map(|x| x * x).sum();
+        for i in 0..3 {
+            torque[i] += t[i];
+            for j in 0..3 {
+                inertia[i][j] += mi * (if i == j { r2 } else { 0.0 } - r[i] * r[j]);
+            }
+        }
+    }
+    let alpha = solve3(inertia, torque);
+    for ((r, fi), mi) in rs.iter().zip(forces.iter_mut()).zip(masses) {
+        let w = cross(alpha, *r);
+        *fi = std::array::from_fn(|k| mi * (a[k] + w[k]));
+    }
+}
+// e31d7a48 ends here
+
+// [[file:../ipi.note::5fa0c3d8][5fa0c3d8]]
+impl Constraints {
+    /// Read constraints from a JSON file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let s = gut::fs::read_file(path)?;
+        let constraints: Self = serde_json::from_str(&s).with_context(|| format!("invalid constraints: {path:?}"))?;
+        constraints.check()?;
+        Ok(constraints)
+    }
+
+    /// Check selection syntax of all constraints. Rigid fragments can not
+    /// overlap with each other or with fixed atoms.
+    pub fn check(&self) -> Result<()> {
+        let mut fixed = std::collections::HashSet::new();
+        if let Some(s) = &self.fixed {
+            fixed.extend(parse_selection(s)?);
+        }
+        for c in self.components.iter() {
+            fixed.extend(parse_selection(&c.atoms)?);
+            parse_components(&c.components)?;
+        }
+        let mut rigid = std::collections::HashSet::new();
+        for s in self.rigid.iter() {
+            for i in parse_selection(s)? {
+                ensure!(!fixed.contains(&i), "atom {i} in rigid fragment {s:?} is also fixed");
+                ensure!(rigid.insert(i), "atom {i} in rigid fragment {s:?} is also in another fragment");
+            }
+        }
+        Ok(())
+    }
+
+    /// Return fixed Cartesian components of each atom in `mol`.
+    fn fixed_components(&self, mol: &Molecule) -> Result<Vec<[bool; 3]>> {
+        let natoms = mol.natoms();
+        let mut fixed = vec![[false; 3]; natoms];
+        let mut fix = |atoms: &[usize], components: [bool; 3]| {
+            for &i in atoms {
+                ensure!(i <= natoms, "invalid atom number in constraints: {i}");
+                for k in 0..3 {
+                    fixed[i - 1][k] |= components[k];
+                }
+            }
+            Ok(())
+        };
+        if self.from_molecule {
+            for (i, atom) in mol.atoms() {
+                fix(&[i], atom.freezing())?;
+            }
+        }
+        if let Some(s) = &self.fixed {
+            fix(&parse_selection(s)?, [true; 3])?;
+        }
+        for c in self.components.iter() {
+            fix(&parse_selection(&c.atoms)?, parse_components(&c.components)?)?;
+        }
+        Ok(fixed)
+    }
+
+    /// Impose constraints on `computed` forces for `mol`: forces of rigid
+    /// fragments are projected onto rigid body motions, and then fixed
+    /// components are zeroed. Energy is unchanged.
+    pub fn apply(&self, mol: &Molecule, computed: &mut Computed) -> Result<()> {
+        let natoms = mol.natoms();
+        ensure!(natoms == computed.forces.len(), "inconsistent number of atoms");
+        self.check()?;
+        let positions: Vec<_> = mol.positions().collect();
+        let masses: Vec<_> = mol.masses().collect();
+        let fixed = self.fixed_components(mol)?;
+        for s in self.rigid.iter() {
+            let atoms = parse_selection(s)?;
+            ensure!(atoms.iter().all(|&i| i <= natoms), "invalid atom number in rigid fragment: {s}");
+            // atoms frozen in molecule
+            if let Some(i) = atoms.iter().find(|&&i| fixed[i - 1].iter().any(|&x| x)) {
+                bail!("atom {i} in rigid fragment {s:?} is also fixed");
+            }
+            let ps: Vec<_> = atoms.iter().map(|&i| positions[i - 1]).collect();
+            let ms: Vec<_> = atoms.iter().map(|&i| masses[i - 1]).collect();
+            let mut fs: Vec<_> = atoms.iter().map(|&i| computed.forces[i - 1]).collect();
+            project_rigid(&ps, &ms, &mut fs);
+            for (&i, f) in atoms.iter().zip(fs) {
+                computed.forces[i - 1] = f;
+            }
+        }
+        for (f, fixed) in computed.forces.iter_mut().zip(fixed) {
+            for k in 0..3 {
+                if fixed[k] {
+                    f[k] = 0.0;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl bridge::Transit for Constraints {
+    fn transit(&mut self, mol: &Molecule, computed: &mut Computed) -> Result<()> {
+        self.apply(mol, computed)
+    }
+}
+
+#[test]
+fn test_constraints() -> Result<()> {
+    use approx::*;
+
+    assert_eq!(parse_selection("1-3, 7,9-10")?, vec![1, 2, 3, 7, 9, 10]);
+    assert!(parse_selection("0-2").is_err());
+    assert!(parse_selection("3-1").is_err());
+    assert!(parse_selection("a").is_err());
+    assert_eq!(parse_components("xZ")?, [true, false, true]);
+    assert!(parse_components("w").is_err());
+
+    let mut mol = Molecule::from_file("tests/files/quinone.cif")?;
+    let natoms = mol.natoms();
+    mol.get_atom_mut(2).unwrap().set_freezing([true; 3]);
+    let forces: Vec<_> = (0..natoms).map(|i| [0.1 * i as f64, 0.2, -0.3 + 0.05 * i as f64]).collect();
+    let mut computed = Computed {
+        energy: -1.0,
+        forces: forces.clone(),
+        virial: [0.0; 9],
+        extra: String::new(),
+    };
+    let constraints: Constraints = serde_json::from_str(
+        r#"{
+            "from_molecule": true,
+            "fixed": "1",
+            "components": [{"atoms": "3-4", "components": "z"}],
+            "rigid": ["5-8"]
+        }"#,
+    )?;
+    constraints.apply(&mol, &mut computed)?;
+    let f = &computed.forces;
+    assert_eq!(computed.energy, -1.0);
+    assert_eq!(f[0], [0.0; 3]);
+    assert_eq!(f[1], [0.0; 3]);
+    assert_eq!(f[2], [forces[2][0], forces[2][1], 0.0]);
+    assert_eq!(f[3], [forces[3][0], forces[3][1], 0.0]);
+
+    // rigid fragment: total force and torque are conserved, and the forces
+    // generate rigid body motion only
+    let positions: Vec<_> = mol.positions().collect();
+    let total = |fs: &[[f64; 3]]| -> [f64; 3] { std::array::from_fn(|k| fs[4..8].iter().map(|f| f[k]).sum()) };
+    let torque = |fs: &[[f64; 3]]| {
+        let mut t = [0.0; 3];
+        for i in 4..8 {
+            let ti = cross(positions[i], fs[i]);
+            for k in 0..3 {
+                t[k] += ti[k];
+            }
+        }
+        t
+    };
+    let (t1, t2) = (torque(&forces), torque(f));
+    let (s1, s2) = (total(&forces), total(f));
+    for k in 0..3 {
+        assert_relative_eq!(s1[k], s2[k], epsilon = 1e-8);
+        assert_relative_eq!(t1[k], t2[k], epsilon = 1e-8);
+    }
+    // the fragment accelerates as a rigid body: a_i = f_i / m_i are the
+    // same for all atoms when projected without rotation
+    let masses: Vec<_> = mol.masses().collect();
+    let same = [[0.0, 0.0, 1.0]; 4];
+    let mut fs: Vec<_> = same.iter().zip(&masses[4..8]).map(|(a, m)| a.map(|x| m * x)).collect();
+    project_rigid(&positions[4..8], &masses[4..8], &mut fs);
+    for (f, m) in fs.iter().zip(&masses[4..8]) {
+        let a = f.map(|x| x / m);
+        for k in 0..3 {
+            assert_relative_eq!(a[k], same[0][k], epsilon = 1e-8);
+        }
+    }
+    // projecting again changes nothing
+    let mut fs = f[4..8].to_vec();
+    project_rigid(&positions[4..8], &masses[4..8], &mut fs);
+    for (a, b) in fs.iter().zip(&f[4..8]) {
+        for k in 0..3 {
+            assert_relative_eq!(a[k], b[k], epsilon = 1e-8);
+        }
+    }
+    // other atoms are untouched
+    assert_eq!(f[8..], forces[8..]);
+
+    // rigid fragments overlapping with fixed atoms are rejected
+    let overlapped = |json: &str| serde_json::from_str::<Constraints>(json).unwrap().check().is_err();
+    assert!(overlapped(r#"{"fixed": "1-4", "rigid": ["4-6"]}"#));
+    assert!(overlapped(r#"{"components": [{"atoms": "5", "components": "z"}], "rigid": ["4-6"]}"#));
+    assert!(overlapped(r#"{"rigid": ["1-3", "3-6"]}"#));
+    assert!(!overlapped(r#"{"fixed": "1-3", "rigid": ["4-6"]}"#));
+    // including atoms frozen in molecule
+    let constraints: Constraints = serde_json::from_str(r#"{"from_molecule": true, "rigid": ["2-4"]}"#)?;
+    assert!(constraints.apply(&mol, &mut computed).is_err());
+
+    Ok(())
+}
+// 5fa0c3d8 ends here
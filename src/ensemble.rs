@@ -0,0 +1,300 @@
+// [[file:../ipi.note::7c2e9d41][7c2e9d41]]
+use super::*;
+use ipi::Heartbeat;
+use socket::{IpiListener, IpiStream};
+use task::TaskReceiver;
+
+use std::io::Write;
+// 7c2e9d41 ends here
+
+// [[file:../ipi.note::b83f0a5e][b83f0a5e]]
+/// Options for committee models
+#[derive(Debug, Clone)]
+pub struct EnsembleOptions {
+    /// The number of drivers in the committee
+    pub nmodels: usize,
+    /// Structures with max force deviation (in eV/Å) larger than this will
+    /// be flagged as uncertain.
+    pub threshold: Option<f64>,
+    /// Append uncertain structures into this file in xyz format, for later
+    /// labelling.
+    pub record: Option<PathBuf>,
+}
+
+/// Combine results computed by committee models, returning the mean energy,
+/// forces and virial. The standard deviation of energy and the max force
+/// deviation are reported in the extra data.
+fn combine(computed: &[Computed]) -> Result<Computed> {
+    let n = computed.len();
+    ensure!(n > 0, "no computed results to combine");
+    let natoms = computed[0].forces.len();
+    ensure!(
+        computed.iter().all(|c| c.forces.len() == natoms),
+        "inconsistent number of atoms in committee"
+    );
+
+    let nf = n as f64;
+    let energies: Vec<_> = computed.iter().map(|c| c.energy).collect();
+    let energy = energies.iter().sum::<f64>() / nf;
+    let energy_std = (energies.iter().map(|e| (e - energy).powi(2)).sum::<f64>() / nf).sqrt();
+
+    let mut forces = vec![[0.0; 3]; natoms];
+    let mut virial = [0.0; 9];
+    for c in computed {
+        for (f, fc) in forces.iter_mut().zip(&c.forces) {
+            for k in 0..3 {
+                f[k] += fc[k] / nf;
+            }
+        }
+        for k in 0..9 {
+            virial[k] += c.virial[k] / nf;
+        }
+    }
+
+    // max over atoms of sqrt(<|F_i - <F_i>|^2>)
+    let max_force_deviation = (0..natoms)
+        .map(|i| {
+            let d2 = computed
+                .iter()
+                .map(|c| (0..3).map(|k| (c.forces[i][k] - forces[i][k]).powi(2)).sum::<f64>())
+                .sum::<f64>();
+            (d2 / nf).sqrt()
+        })
+        .fold(0.0, f64::max);
+
+    let mut combined = Computed {
+        energy,
+        forces,
+        virial,
+        extra: String::new(),
+    };
+    combined.insert_extra("energies", energies.into());
+    combined.insert_extra("energy_std", energy_std.into());
+    combined.insert_extra("max_force_deviation", max_force_deviation.into());
+
+    Ok(combined)
+}
+
+#[test]
+fn test_ensemble_combine() {
+    use approx::*;
+
+    let c1 = Computed {
+        energy: 1.0,
+        forces: vec![[1.0, 0.0, 0.0], [0.0, 0.0, 0.0]],
+        virial: [0.0; 9],
+        extra: String::new(),
+    };
+    let c2 = Computed {
+        energy: 3.0,
+        forces: vec![[-1.0, 0.0, 0.0], [0.0, 0.5, 0.0]],
+        virial: [0.0; 9],
+        extra: "{}".into(),
+    };
+    let c = combine(&[c1, c2]).unwrap();
+    assert_relative_eq!(c.energy, 2.0);
+    assert_relative_eq!(c.forces[0][0], 0.0);
+    assert_relative_eq!(c.forces[1][1], 0.25);
+    let extra: serde_json::Value = serde_json::from_str(&c.extra).unwrap();
+    assert_relative_eq!(extra["energy_std"].as_f64().unwrap(), 1.0);
+    assert_relative_eq!(extra["max_force_deviation"].as_f64().unwrap(), 1.0);
+}
+// b83f0a5e ends here
+
+// [[file:../ipi.note::e41d6a93][e41d6a93]]
+/// Append `mol` into `path` in xyz format.
+fn record_uncertain(path: &Path, mol: &Molecule, deviation: f64) -> Result<()> {
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open {path:?} for recording"))?;
+    writeln!(f, "{}", mol.natoms())?;
+    writeln!(f, "{} max_force_deviation={deviation:.6}", mol.title())?;
+    for (s, [x, y, z]) in mol.symbols().zip(mol.positions()) {
+        writeln!(f, "{s:3} {x:18.8} {y:18.8} {z:18.8}")?;
+    }
+    Ok(())
+}
+
+/// Flag `combined` results of `mol` as uncertain if its max force deviation
+/// exceeds threshold in `options`, and record it if required.
+fn flag_uncertain(combined: &mut Computed, mol: &Molecule, options: &EnsembleOptions) -> Result<()> {
+    if let Some(threshold) = options.threshold {
+        let extra: serde_json::Value = serde_json::from_str(&combined.extra)?;
+        let deviation = extra["max_force_deviation"].as_f64().unwrap_or_default();
+        let uncertain = deviation > threshold;
+        if uncertain {
+            warn!("high uncertainty for {}: max force deviation = {deviation}", mol.title());
+            if let Some(path) = &options.record {
+                // NOTE: failed recording should not stop serving
+                if let Err(err) = record_uncertain(path, mol, deviation) {
+                    error!("failed to record uncertain structure: {err:?}");
+                }
+            }
+        }
+        combined.insert_extra("uncertain", uncertain.into());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_flag_uncertain() -> Result<()> {
+    let mol = Molecule::from_file("tests/files/quinone.cif")?;
+    let forces = |x: f64| vec![[x, 0.0, 0.0]; mol.natoms()];
+    let computed = [-1.0, 1.0].map(|x| Computed {
+        energy: 0.0,
+        forces: forces(x),
+        virial: [0.0; 9],
+        extra: String::new(),
+    });
+    let mut combined = combine(&computed)?;
+    // recording into an invalid path is not fatal
+    let options = EnsembleOptions {
+        nmodels: 2,
+        threshold: Some(0.5),
+        record: Some("/nonexistent/uncertain.xyz".into()),
+    };
+    flag_uncertain(&mut combined, &mol, &options)?;
+    let extra: serde_json::Value = serde_json::from_str(&combined.extra)?;
+    assert_eq!(extra["uncertain"], true);
+
+    Ok(())
+}
+
+/// Broadcast `mol` to all `drivers`, and return the combined results.
+async fn compute_broadcast(drivers: &mut [IpiStream], mol: Molecule, options: &EnsembleOptions) -> Result<Computed> {
+    let jobs = drivers.iter_mut().map(|d| d.compute_one(mol.clone()));
+    let computed = futures::future::try_join_all(jobs).await?;
+    let mut combined = combine(&computed)?;
+    flag_uncertain(&mut combined, &mol, options)?;
+
+    Ok(combined)
+}
+
+/// Serve molecule computation requests from `task` using committee of ready
+/// `drivers`, until the task channel is closed. Idle drivers are polled as
+/// in `heartbeat`, and all drivers are required to be alive.
+async fn serve_ensemble(
+    mut drivers: Vec<IpiStream>,
+    mut task: TaskReceiver,
+    options: &EnsembleOptions,
+    heartbeat: Option<Heartbeat>,
+) -> Result<()> {
+    loop {
+        debug!("wait for new molecule to compute ...");
+        let received = match heartbeat {
+            None => task.recv().await,
+            Some(heartbeat) => loop {
+                tokio::select! {
+                    received = task.recv() => break received,
+                    _ = tokio::time::sleep(heartbeat.interval) => {
+                        for d in drivers.iter_mut() {
+                            d.heartbeat(heartbeat.timeout).await?;
+                        }
+                    }
+                }
+            },
+        };
+        if let Some((mol, tx_out)) = received {
+            debug!("ask {} drivers to compute molecule {}", drivers.len(), mol.title());
+            let computed = compute_broadcast(&mut drivers, mol, options).await?;
+            // NOTE: the client could be gone, which is not an error here
+            let _ = tx_out.send(computed);
+        } else {
+            // task channel closed for some reason
+            for d in drivers.iter_mut() {
+                d.shutdown().await;
+            }
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+impl IpiListener {
+    /// Serve molecule computation requests from `task` using committee
+    /// models: each molecule is broadcast to all connected drivers, and the
+    /// mean energy and forces are returned. Idle drivers are polled as in
+    /// `heartbeat`, and the committee stops if any driver is dead.
+    pub async fn serve_channel_ensemble(
+        &self,
+        task: TaskReceiver,
+        options: &EnsembleOptions,
+        heartbeat: Option<Heartbeat>,
+    ) -> Result<()> {
+        info!("i-PI server: wait for {} drivers to connect ...", options.nmodels);
+        let mut drivers = vec![];
+        for i in 0..options.nmodels {
+            let mut stream = self.accept().await?;
+            stream.wait_until_ready().await?;
+            info!("driver {} is ready now ...", i + 1);
+            drivers.push(stream);
+        }
+        serve_ensemble(drivers, task, options, heartbeat).await
+    }
+}
+
+#[tokio::test]
+async fn test_serve_ensemble() -> Result<()> {
+    // two drivers in committee returning different energies
+    let mut drivers = vec![];
+    let mut handles = vec![];
+    for energy in [1.0, 3.0] {
+        let (server, driver) = tokio::io::duplex(1 << 16);
+        let mut stream = IpiStream::from_stream(server);
+        handles.push(tokio::spawn(ipi::fake_driver(driver, move |mol| Computed {
+            energy,
+            forces: vec![[0.0; 3]; mol.natoms()],
+            virial: [0.0; 9],
+            extra: String::new(),
+        })));
+        stream.wait_until_ready().await?;
+        drivers.push(stream);
+    }
+    let (task_rx, task_tx) = task::Task::new().split();
+    let options = EnsembleOptions {
+        nmodels: 2,
+        threshold: None,
+        record: None,
+    };
+    let served = tokio::spawn(async move { serve_ensemble(drivers, task_rx, &options, None).await });
+
+    let mol = Molecule::from_file("tests/files/quinone.cif")?;
+    let computed = task_tx.remote_compute(mol).await?;
+    assert_eq!(computed.energy, 2.0);
+
+    // all drivers exit when task channel closed
+    drop(task_tx);
+    served.await??;
+    for h in handles {
+        assert_eq!(h.await??, 1);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ensemble_heartbeat() -> Result<()> {
+    use std::time::Duration;
+
+    // a driver never answering, but keeping the connection open
+    let (server, _driver) = tokio::io::duplex(1 << 16);
+    let drivers = vec![IpiStream::from_stream(server)];
+    let (task_rx, _task_tx) = task::Task::new().split();
+    let options = EnsembleOptions {
+        nmodels: 1,
+        threshold: None,
+        record: None,
+    };
+    let heartbeat = Heartbeat {
+        interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(100),
+    };
+    let served = serve_ensemble(drivers, task_rx, &options, Some(heartbeat));
+    assert!(tokio::time::timeout(Duration::from_secs(1), served).await?.is_err());
+
+    Ok(())
+}
+// e41d6a93 ends here
@@ -0,0 +1,368 @@
+// [[file:../ipi.note::ea13876d][ea13876d]]
+use super::*;
+use task::TaskSender;
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use vecfx::nalgebra::DMatrix;
+// ea13876d ends here
+
+// [[file:../ipi.note::9056f2ae][9056f2ae]]
+/// Conversion factor from sqrt(eV/Å^2/amu) to wavenumber in cm^-1
+const EV_A2_AMU_TO_CM: f64 = 521.4709;
+/// h*c in eV*cm, for converting wavenumber in cm^-1 to energy in eV
+const HC_EV_CM: f64 = 1.239841984e-4;
+
+/// Options for finite-difference Hessian calculation
+#[derive(Debug, Clone)]
+pub struct HessianOptions {
+    /// The displacement step size in Å
+    pub delta: f64,
+    /// Max number of displacements computed at the same time
+    pub nparallel: usize,
+    /// Checkpoint file for saving partial results. Computed displacements
+    /// found in this file will be reused.
+    pub checkpoint: Option<PathBuf>,
+}
+
+impl Default for HessianOptions {
+    fn default() -> Self {
+        Self {
+            delta: 0.01,
+            nparallel: 1,
+            checkpoint: None,
+        }
+    }
+}
+
+/// The results of harmonic vibrational analysis
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VibrationalAnalysis {
+    /// Symmetrised Cartesian Hessian matrix in eV/Å^2, in row major order
+    pub hessian: Vec<f64>,
+    /// Harmonic frequencies in cm^-1. Imaginary frequencies are represented
+    /// as negative values.
+    pub frequencies: Vec<f64>,
+    /// Normalized Cartesian displacements of each normal mode
+    pub normal_modes: Vec<Vec<[f64; 3]>>,
+    /// Zero-point energy in eV
+    pub zpe: f64,
+}
+// 9056f2ae ends here
+
+// [[file:../ipi.note::24d9241d][24d9241d]]
+/// Forces of displaced structures saved for restart
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    delta: f64,
+    natoms: usize,
+    /// Element symbols of the reference structure
+    #[serde(default)]
+    symbols: Vec<String>,
+    /// Positions of the reference structure in Å
+    #[serde(default)]
+    positions: Vec<[f64; 3]>,
+    /// Computed forces indexed by displacement key
+    forces: BTreeMap<String, Vec<[f64; 3]>>,
+}
+
+impl Checkpoint {
+    fn load(path: &Path) -> Result<Self> {
+        let s = gut::fs::read_file(path)?;
+        let ckpt = serde_json::from_str(&s).with_context(|| format!("invalid checkpoint file: {path:?}"))?;
+        Ok(ckpt)
+    }
+
+    /// Check if this checkpoint is saved for the same structure `mol` and
+    /// step size `delta`.
+    fn check(&self, mol: &Molecule, delta: f64) -> Result<()> {
+        ensure!(self.natoms == mol.natoms(), "different number of atoms");
+        ensure!(mol.symbols().eq(self.symbols.iter().map(|s| s.as_str())), "different species");
+        let same_positions = mol
+            .positions()
+            .zip(&self.positions)
+            .all(|(p, q)| (0..3).all(|k| (p[k] - q[k]).abs() < 1e-6));
+        ensure!(self.positions.len() == self.natoms && same_positions, "different positions");
+        ensure!((self.delta - delta).abs() < 1e-8, "different step size");
+        Ok(())
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let s = serde_json::to_string(self)?;
+        gut::fs::write_to_file(path, &s)?;
+        Ok(())
+    }
+}
+
+/// The key for displacing atom `i` along Cartesian direction `k` in positive
+/// (`sign` > 0) or negative direction.
+fn displacement_key(i: usize, k: usize, sign: f64) -> String {
+    let s = if sign > 0.0 { "+" } else { "-" };
+    format!("{i}{}{s}", ["x", "y", "z"][k])
+}
+
+#[test]
+fn test_checkpoint_check() -> Result<()> {
+    let mol = Molecule::from_file("tests/files/quinone.cif")?;
+    let ckpt = Checkpoint {
+        delta: 0.01,
+        natoms: mol.natoms(),
+        symbols: mol.symbols().map(|s| s.to_owned()).collect(),
+        positions: mol.positions().collect(),
+        ..Default::default()
+    };
+    assert!(ckpt.check(&mol, 0.01).is_ok());
+    assert!(ckpt.check(&mol, 0.02).is_err());
+
+    // same number of atoms, but a different structure
+    let mut other = mol.clone();
+    let positions: Vec<_> = mol.positions().map(|[x, y, z]| [x + 0.1, y, z]).collect();
+    other.set_positions(positions);
+    assert!(ckpt.check(&other, 0.01).is_err());
+    // checkpoint saved without reference structure
+    let old = Checkpoint {
+        delta: 0.01,
+        natoms: mol.natoms(),
+        ..Default::default()
+    };
+    assert!(old.check(&mol, 0.01).is_err());
+
+    Ok(())
+}
+// 24d9241d ends here
+
+// [[file:../ipi.note::4bc78c72][4bc78c72]]
+/// Compute forces of all displaced structures of `mol`, dispatching them
+/// through `task`.
+async fn compute_displaced_forces(task: &TaskSender, mol: &Molecule, options: &HessianOptions) -> Result<Checkpoint> {
+    let natoms = mol.natoms();
+    let delta = options.delta;
+
+    let mut ckpt = match &options.checkpoint {
+        Some(path) if path.exists() => {
+            let ckpt = Checkpoint::load(path)?;
+            ckpt.check(mol, delta)
+                .with_context(|| format!("checkpoint {path:?} is not for this structure; remove it to start over"))?;
+            info!("restart from {} computed displacements in {path:?}", ckpt.forces.len());
+            ckpt
+        }
+        _ => Checkpoint {
+            delta,
+            natoms,
+            symbols: mol.symbols().map(|s| s.to_owned()).collect(),
+            positions: mol.positions().collect(),
+            ..Default::default()
+        },
+    };
+
+    let positions: Vec<_> = mol.positions().collect();
+    let mut jobs = vec![];
+    for i in 0..natoms {
+        for k in 0..3 {
+            for sign in [1.0, -1.0] {
+                let key = displacement_key(i, k, sign);
+                if ckpt.forces.contains_key(&key) {
+                    continue;
+                }
+                let mut displaced = positions.clone();
+                displaced[i][k] += sign * delta;
+                let mut mol = mol.clone();
+                mol.set_positions(displaced);
+                mol.set_title(&key);
+                jobs.push((key, mol));
+            }
+        }
+    }
+    info!("{} displacements to compute", jobs.len());
+
+    let nparallel = options.nparallel.max(1);
+    let mut computed = futures::stream::iter(jobs)
+        .map(|(key, mol)| async move { (key, task.remote_compute(mol).await) })
+        .buffer_unordered(nparallel);
+    while let Some((key, c)) = computed.next().await {
+        let c = c.with_context(|| format!("failed to compute displacement {key}"))?;
+        ensure!(c.forces.len() == natoms, "invalid forces for displacement {key}");
+        ensure!(
+            c.forces.iter().flatten().all(|x| x.is_finite()),
+            "driver returned non-finite forces for displacement {key}"
+        );
+        ckpt.forces.insert(key, c.forces);
+        if let Some(path) = &options.checkpoint {
+            ckpt.save(path)?;
+        }
+    }
+
+    Ok(ckpt)
+}
+// 4bc78c72 ends here
+
+// [[file:../ipi.note::c989cf87][c989cf87]]
+/// Assemble symmetrised Hessian matrix in row major order from forces of
+/// displaced structures.
+fn assemble_hessian(ckpt: &Checkpoint) -> Result<Vec<f64>> {
+    let natoms = ckpt.natoms;
+    let n = 3 * natoms;
+    let mut hessian = vec![0.0; n * n];
+    for i in 0..natoms {
+        for k in 0..3 {
+            let get = |sign| {
+                let key = displacement_key(i, k, sign);
+                ckpt.forces.get(&key).ok_or(format_err!("missing forces for displacement {key}"))
+            };
+            let fp = get(1.0)?;
+            let fm = get(-1.0)?;
+            let row = 3 * i + k;
+            for j in 0..natoms {
+                for l in 0..3 {
+                    hessian[row * n + 3 * j + l] = -(fp[j][l] - fm[j][l]) / (2.0 * ckpt.delta);
+                }
+            }
+        }
+    }
+
+    // symmetrise
+    for a in 0..n {
+        for b in 0..a {
+            let x = 0.5 * (hessian[a * n + b] + hessian[b * n + a]);
+            hessian[a * n + b] = x;
+            hessian[b * n + a] = x;
+        }
+    }
+
+    Ok(hessian)
+}
+
+/// Harmonic vibrational analysis of the Cartesian `hessian` (in eV/Å^2)
+/// with atom `masses` in amu. `nrigid` is the number of translational and
+/// rotational modes which will be excluded in zero-point energy.
+fn vibrational_analysis(hessian: Vec<f64>, masses: &[f64], nrigid: usize) -> VibrationalAnalysis {
+    let natoms = masses.len();
+    let n = 3 * natoms;
+    assert_eq!(hessian.len(), n * n);
+
+    let m: Vec<_> = masses.iter().flat_map(|&m| [m; 3]).collect();
+    let mut mw = DMatrix::from_row_slice(n, n, &hessian);
+    for a in 0..n {
+        for b in 0..n {
+            mw[(a, b)] /= (m[a] * m[b]).sqrt();
+        }
+    }
+
+    let eigen = mw.symmetric_eigen();
+    let mut order: Vec<_> = (0..n).collect();
+    order.sort_by(|&a, &b| eigen.eigenvalues[a].total_cmp(&eigen.eigenvalues[b]));
+
+    let mut frequencies = vec![];
+    let mut normal_modes = vec![];
+    for &i in order.iter() {
+        let ev = eigen.eigenvalues[i];
+        frequencies.push(ev.signum() * ev.abs().sqrt() * EV_A2_AMU_TO_CM);
+        // back to Cartesian displacements
+        let mut mode: Vec<_> = (0..n).map(|a| eigen.eigenvectors[(a, i)] / m[a].sqrt()).collect();
+        let norm = mode.iter().map(|x| x * x).sum::<f64>().sqrt();
+        mode.iter_mut().for_each(|x| *x /= norm);
+        normal_modes.push(mode.chunks(3).map(|x| [x[0], x[1], x[2]]).collect());
+    }
+
+    // exclude translational and rotational modes which have the smallest
+    // absolute frequencies
+    let mut vib = frequencies.clone();
+    vib.sort_by(|a, b| a.abs().total_cmp(&b.abs()));
+    let zpe = vib.iter().skip(nrigid).filter(|&&f| f > 0.0).sum::<f64>() * 0.5 * HC_EV_CM;
+
+    VibrationalAnalysis {
+        hessian,
+        frequencies,
+        normal_modes,
+        zpe,
+    }
+}
+
+/// Return true if all atoms at `positions` are on a line within 1e-4 Å.
+fn is_linear(positions: &[[f64; 3]]) -> bool {
+    let p0 = match positions.first() {
+        Some(&p0) => p0,
+        None => return true,
+    };
+    let d = |p: &[f64; 3]| -> [f64; 3] { std::array::from_fn(|k| p[k] - p0[k]) };
+    let norm = |v: [f64; 3]| v.iter().map(|x| x * x).sum::<f64>().sqrt();
+    // the axis along the atom farthest from the first one
+    let axis = positions.iter().map(d).max_by(|a, b| norm(*a).total_cmp(&norm(*b))).unwrap();
+    let length = norm(axis);
+    if length < 1e-4 {
+        return true;
+    }
+    positions.iter().map(d).all(|v| {
+        let c = [
+            v[1] * axis[2] - v[2] * axis[1],
+            v[2] * axis[0] - v[0] * axis[2],
+            v[0] * axis[1] - v[1] * axis[0],
+        ];
+        norm(c) / length < 1e-4
+    })
+}
+
+/// Return the number of translational and rotational modes of `mol`.
+fn count_rigid_modes(mol: &Molecule) -> usize {
+    let positions: Vec<_> = mol.positions().collect();
+    if mol.is_periodic() || positions.len() == 1 {
+        3
+    } else if is_linear(&positions) {
+        5
+    } else {
+        6
+    }
+}
+
+#[test]
+fn test_is_linear() {
+    assert!(is_linear(&[[0.0; 3], [1.1, 0.0, 0.0]]));
+    // CO2 along an arbitrary direction
+    assert!(is_linear(&[[0.0; 3], [1.16, 1.16, 1.16], [-1.16, -1.16, -1.16]]));
+    // water
+    assert!(!is_linear(&[[0.0; 3], [0.96, 0.0, 0.0], [-0.24, 0.93, 0.0]]));
+}
+
+#[test]
+fn test_vibrational_analysis() {
+    use approx::*;
+
+    // a diatomic harmonic oscillator along x axis: k = 36.0 eV/Å^2
+    let k = 36.0;
+    let mut hessian = vec![0.0; 36];
+    // (x1, x1), (x2, x2), (x1, x2), (x2, x1)
+    hessian[0] = k;
+    hessian[21] = k;
+    hessian[3] = -k;
+    hessian[18] = -k;
+    let masses = [1.0, 1.0];
+    let vib = vibrational_analysis(hessian, &masses, 5);
+    // reduced mass 0.5 amu
+    let freq = (k / 0.5).sqrt() * EV_A2_AMU_TO_CM;
+    assert_relative_eq!(vib.frequencies[5], freq, epsilon = 1e-6);
+    assert_relative_eq!(vib.zpe, 0.5 * freq * HC_EV_CM, epsilon = 1e-8);
+    let [x1, _, _] = vib.normal_modes[5][0];
+    let [x2, _, _] = vib.normal_modes[5][1];
+    assert_relative_eq!(x1, -x2, epsilon = 1e-8);
+}
+// c989cf87 ends here
+
+// [[file:../ipi.note::00939242][00939242]]
+/// Compute the Hessian matrix of `mol` by finite difference of forces, and
+/// carry out harmonic vibrational analysis.
+///
+/// Each atom is displaced by ±`delta` along x/y/z, and forces of displaced
+/// structures are computed through `task` by the connected drivers.
+pub async fn compute_hessian(task: &TaskSender, mol: &Molecule, options: &HessianOptions) -> Result<VibrationalAnalysis> {
+    ensure!(options.delta > 0.0, "invalid displacement step size: {}", options.delta);
+    let ckpt = compute_displaced_forces(task, mol, options).await?;
+    let hessian = assemble_hessian(&ckpt)?;
+
+    let masses: Vec<_> = mol.masses().collect();
+    let nrigid = count_rigid_modes(mol);
+    let vib = vibrational_analysis(hessian, &masses, nrigid);
+
+    Ok(vib)
+}
+// 00939242 ends here
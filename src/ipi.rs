@@ -4,16 +4,14 @@ use socket::*;
 
 use futures::SinkExt;
 use futures::StreamExt;
-use std::path::{Path, PathBuf};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::{TcpStream, UnixStream};
-use tokio_util::codec::Decoder;
 use tokio_util::codec::{FramedRead, FramedWrite};
 // ac2d8efb ends here
 
 // [[file:../ipi.note::104ce11f][104ce11f]]
-/// The communication between the i-PI client and server.
-struct IpiServerStream<R, W>
+/// The communication between the i-PI client and server, viewed from the
+/// server side.
+pub(crate) struct IpiServerStream<R, W>
 where
     R: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
@@ -27,15 +25,26 @@ where
     R: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
 {
-    fn new(read: R, write: W) -> Self {
+    pub(crate) fn new(read: R, write: W) -> Self {
         // the message we received from the client code (VASP, SIESTA, ...)
-        let mut read = FramedRead::new(read, codec::ClientCodec);
+        let read = FramedRead::new(read, codec::ClientCodec::default());
         // the message we sent to the client
-        let mut write = FramedWrite::new(write, codec::ServerCodec);
+        let write = FramedWrite::new(write, codec::ServerCodec::default());
 
         Self { read, write }
     }
 
+    /// Return the underlying reader and writer.
+    pub(crate) fn into_inner(self) -> (R, W) {
+        (self.read.into_inner(), self.write.into_inner())
+    }
+
+    /// Exchange messages in binary layout specified in `opts`.
+    pub(crate) fn set_codec(&mut self, opts: codec::CodecOptions) {
+        *self.read.decoder_mut() = codec::ClientCodec::new(opts);
+        *self.write.encoder_mut() = codec::ServerCodec::new(opts);
+    }
+
     /// Ask and return client status
     async fn get_status(&mut self) -> Result<ClientStatus> {
         self.write.send(ServerMessage::Status).await?;
@@ -65,6 +74,8 @@ where
 
     /// Send input data (the position and cell data) to the client.
     async fn set_input(&mut self, mol: Molecule) -> Result<()> {
+        // FORCEREADY will be checked against the number of atoms sent
+        self.read.decoder_mut().expect_natoms(mol.natoms());
         self.write.send(ServerMessage::PosData(mol)).await?;
         Ok(())
     }
@@ -72,103 +83,328 @@ where
     /// Send the init string to the client.
     // FIXME: handle the json part
     async fn set_init(&mut self) -> Result<()> {
-        let init = InitData::new(0, "");
+        // NOTE: follow ASE to send one zero byte, since some codes may not
+        // work with empty init string
+        let init = InitData::new(0, "\0");
         self.write.send(ServerMessage::Init(init)).await?;
         Ok(())
     }
 }
 // 104ce11f ends here
 
-// [[file:../ipi.note::32f96fbd][32f96fbd]]
-// wait until client ready to compute molecule
-macro_rules! process_client_stream {
-    ($stream: expr) => {{
-        let (read, write) = $stream.split();
-        let mut stream = IpiServerStream::new(read, write);
+// [[file:../ipi.note::7e3d5b92][7e3d5b92]]
+/// The state of driver in i-PI protocol, as seen from server side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DriverState {
+    /// Connected, but its status is unknown yet
+    Disconnected,
+    /// Waiting for INIT
+    NeedInit,
+    /// Ready to receive POSDATA
+    Ready,
+    /// POSDATA sent, and computing
+    Computing,
+    /// Forces are ready for GETFORCE
+    HaveData,
+}
+
+/// The next step for server to take
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Send INIT
+    Init,
+    /// Send POSDATA
+    PosData,
+    /// Poll STATUS again after a while, since driver is busy
+    Wait,
+    /// Retrieve forces with GETFORCE
+    GetForce,
+    /// Retrieve and discard stale forces left by previous request, e.g.
+    /// when driver reconnected
+    DiscardForce,
+}
+
+impl DriverState {
+    /// Return next state and action for `status` answered by driver.
+    /// Illegal transitions are reported as errors.
+    fn on_status(self, status: ClientStatus) -> Result<(Self, Action)> {
+        use ClientStatus as S;
+        use DriverState::*;
+
+        let next = match (self, status) {
+            // some codes answer READY a few times before HAVEDATA
+            (Computing, S::Ready) => (Computing, Action::Wait),
+            (Computing, S::HaveData) => (HaveData, Action::GetForce),
+            (Computing, S::NeedInit) => bail!("driver asks for INIT during computation"),
+            (HaveData, s) => bail!("driver status {s:?} polled before retrieving forces"),
+            (_, S::NeedInit) => (NeedInit, Action::Init),
+            (_, S::Ready) => (Ready, Action::PosData),
+            (_, S::HaveData) => (HaveData, Action::DiscardForce),
+            (_, s @ (S::Disconnected | S::TimeOut)) => bail!("driver is unavailable: {s:?}"),
+            // NOTE: never decoded from i-PI messages
+            (_, S::Up) => bail!("unexpected driver status: Up"),
+        };
+        Ok(next)
+    }
+
+    /// Return next state after `action` done.
+    fn after(self, action: Action) -> Self {
+        match action {
+            Action::Init => DriverState::NeedInit,
+            Action::PosData => DriverState::Computing,
+            Action::GetForce | Action::DiscardForce => DriverState::Ready,
+            Action::Wait => self,
+        }
+    }
+}
+
+#[test]
+fn test_driver_state() {
+    use ClientStatus as S;
+    use DriverState::*;
+
+    // normal computation
+    let (state, action) = Disconnected.on_status(S::NeedInit).unwrap();
+    assert_eq!(action, Action::Init);
+    let (state, action) = state.after(action).on_status(S::Ready).unwrap();
+    assert_eq!(action, Action::PosData);
+    let state = state.after(action);
+    assert_eq!(state, Computing);
+    assert_eq!(state.on_status(S::Ready).unwrap(), (Computing, Action::Wait));
+    let (state, action) = state.on_status(S::HaveData).unwrap();
+    assert_eq!((state, action), (HaveData, Action::GetForce));
+    assert_eq!(state.after(action), Ready);
+
+    // stale data on reconnect
+    assert_eq!(Disconnected.on_status(S::HaveData).unwrap(), (HaveData, Action::DiscardForce));
+    assert_eq!(Ready.on_status(S::HaveData).unwrap(), (HaveData, Action::DiscardForce));
+
+    // illegal transitions
+    assert!(Computing.on_status(S::NeedInit).is_err());
+    assert!(HaveData.on_status(S::Ready).is_err());
+    assert!(Ready.on_status(S::TimeOut).is_err());
+}
+// 7e3d5b92 ends here
+
+// [[file:../ipi.note::c18f4e06][c18f4e06]]
+use std::time::Duration;
 
+/// The delays for polling STATUS of busy driver
+const POLL_DELAY_MIN: Duration = Duration::from_millis(1);
+const POLL_DELAY_MAX: Duration = Duration::from_millis(500);
+/// Max number of INIT sent before driver being ready
+const MAX_INIT: usize = 3;
+
+impl<R, W> IpiServerStream<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Drive the driver with STATUS polling until it is ready, and then
+    /// compute `mol` if any.
+    async fn drive(&mut self, mut mol: Option<Molecule>) -> Result<Option<Computed>> {
+        let natoms = mol.as_ref().map_or(0, |mol| mol.natoms());
+        let mut state = DriverState::Disconnected;
+        let mut delay = POLL_DELAY_MIN;
+        let mut ninit = 0;
         loop {
-            // ask for client status
-            let status = stream.get_status().await?;
-            match status {
-                ClientStatus::NeedInit => {
-                    stream.set_init().await?;
+            let status = self.get_status().await?;
+            let (next, action) = state.on_status(status)?;
+            debug!("driver state: {state:?} => {next:?}, {action:?}");
+            match action {
+                Action::Init => {
+                    ninit += 1;
+                    ensure!(ninit <= MAX_INIT, "driver is not ready after {MAX_INIT} INIT");
+                    self.set_init().await?;
+                }
+                Action::PosData => match mol.take() {
+                    Some(mol) => self.set_input(mol).await?,
+                    None => return Ok(None),
+                },
+                Action::Wait => {
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(POLL_DELAY_MAX);
                 }
-                ClientStatus::Ready => {
-                    break;
+                Action::GetForce => {
+                    let computed = self.get_computed().await?;
+                    ensure!(
+                        computed.forces.len() == natoms,
+                        "driver sent forces of {} atoms in FORCEREADY, but {natoms} atoms in POSDATA",
+                        computed.forces.len()
+                    );
+                    return Ok(Some(computed));
                 }
-                _ => unimplemented!(),
+                Action::DiscardForce => {
+                    warn!("discard stale forces left in driver");
+                    self.get_computed().await?;
+                }
+            }
+            if action != Action::Wait {
+                delay = POLL_DELAY_MIN;
+            }
+            state = next.after(action);
+        }
+    }
+
+    /// Wait until driver ready to compute molecule
+    async fn wait_until_ready(&mut self) -> Result<()> {
+        self.drive(None).await?;
+        Ok(())
+    }
+
+    /// Poll STATUS of idle driver, which is declared dead if not answered
+    /// within `timeout`.
+    async fn heartbeat(&mut self, timeout: Duration) -> Result<()> {
+        let status = tokio::time::timeout(timeout, self.get_status())
+            .await
+            .map_err(|_| format_err!("driver did not answer STATUS within {timeout:?}"))??;
+        debug!("driver heartbeat: {status:?}");
+        Ok(())
+    }
+
+    /// Compute one molecule, and return computed properties
+    ///
+    /// NOTE: the driver status is polled before sending POSDATA in each step,
+    /// in the same order as ASE's SocketIOCalculator, since some drivers
+    /// (e.g. ASE's SocketClient) go back to NEEDINIT after sending forces.
+    async fn compute(&mut self, mol: Molecule) -> Result<Computed> {
+        let computed = self.drive(Some(mol)).await?;
+        computed.ok_or(format_err!("no forces computed"))
+    }
+}
+
+#[tokio::test]
+async fn test_drive_driver() -> Result<()> {
+    use ClientStatus as S;
+
+    // a driver answering READY twice after POSDATA, with stale forces left
+    // in the beginning
+    let (server, driver) = tokio::io::duplex(1 << 16);
+    let fake_driver = tokio::spawn(async move {
+        let (read, write) = tokio::io::split(driver);
+        let mut read = FramedRead::new(read, codec::ServerCodec::default());
+        let mut write = FramedWrite::new(write, codec::ClientCodec::default());
+        let computed = |energy, natoms| Computed {
+            energy,
+            forces: vec![[0.0; 3]; natoms],
+            virial: [0.0; 9],
+            extra: String::new(),
+        };
+        let mut replies = vec![S::HaveData, S::NeedInit, S::Ready, S::Ready, S::Ready, S::HaveData].into_iter();
+        let mut energies = vec![];
+        let mut natoms = 3;
+        while let Some(msg) = read.next().await {
+            match msg? {
+                ServerMessage::Status => write.send(ClientMessage::Status(replies.next().unwrap())).await?,
+                ServerMessage::PosData(mol) => natoms = mol.natoms(),
+                ServerMessage::GetForce => {
+                    let energy = if energies.is_empty() { -1.0 } else { -2.0 };
+                    energies.push(energy);
+                    write.send(ClientMessage::ForceReady(computed(energy, natoms))).await?;
+                }
+                ServerMessage::Exit => break,
+                _ => {}
             }
         }
-    }};
+        Ok::<_, std::io::Error>(energies)
+    });
+
+    let (read, write) = tokio::io::split(server);
+    let mut stream = IpiServerStream::new(read, write);
+    let mol = Molecule::from_file("tests/files/quinone.cif")?;
+    let computed = stream.compute(mol).await?;
+    // stale forces discarded
+    assert_eq!(computed.energy, -2.0);
+    stream.set_exit().await?;
+    assert_eq!(fake_driver.await??, vec![-1.0, -2.0]);
+
+    Ok(())
 }
 
-// compute one molecule, and return computed properties
-macro_rules! process_client_stream_compute {
-    ($stream: expr, $mol: expr) => {{
-        let (read, write) = $stream.split();
-        let mut stream = IpiServerStream::new(read, write);
+#[tokio::test]
+async fn test_forces_mismatch() -> Result<()> {
+    // a driver sending forces of one atom only
+    let (server, driver) = tokio::io::duplex(1 << 16);
+    tokio::spawn(fake_driver(driver, |_| Computed {
+        energy: 0.0,
+        forces: vec![[0.0; 3]],
+        virial: [0.0; 9],
+        extra: String::new(),
+    }));
 
-        // client is ready, and we send the mol to compute
-        stream.set_input($mol).await?;
-        let status = stream.get_status().await?;
-        assert_eq!(status, ClientStatus::HaveData);
+    let (read, write) = tokio::io::split(server);
+    let mut stream = IpiServerStream::new(read, write);
+    let mol = Molecule::from_file("tests/files/quinone.cif")?;
+    assert!(stream.compute(mol).await.is_err());
 
-        let computed = stream.get_computed().await?;
-        return Ok(computed);
-    }};
+    Ok(())
 }
-// 32f96fbd ends here
+// c18f4e06 ends here
 
 // [[file:../ipi.note::680b1817][680b1817]]
+use futures::Stream;
 use task::TaskReceiver;
 
-impl IpiStream {
-    async fn wait_until_ready(&mut self) -> Result<()> {
-        match self {
-            IpiStream::Tcp(s) => {
-                process_client_stream!(s);
-            }
-            IpiStream::Unix(s) => {
-                process_client_stream!(s);
-            }
-        };
+use std::sync::Arc;
+use tokio::sync::{Mutex, Notify};
 
-        Ok(())
+/// Task receiver shared by all connected drivers
+type SharedTaskReceiver = Arc<Mutex<TaskReceiver>>;
+
+/// Polling idle drivers with STATUS to find dead ones
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    /// Poll idle driver after this interval
+    pub interval: Duration,
+    /// Driver not answering STATUS within this time is declared dead
+    pub timeout: Duration,
+}
+
+impl IpiStream {
+    /// Wait until driver ready to compute molecule
+    pub(crate) async fn wait_until_ready(&mut self) -> Result<()> {
+        self.0.wait_until_ready().await
     }
 
-    async fn compute_one(&mut self, mol: Molecule) -> Result<Computed> {
-        let computed = match self {
-            IpiStream::Tcp(s) => {
-                process_client_stream_compute!(s, mol);
-            }
-            IpiStream::Unix(s) => {
-                process_client_stream_compute!(s, mol);
-            }
-        };
-        Ok(computed)
+    /// Compute one molecule, and return computed properties
+    pub(crate) async fn compute_one(&mut self, mol: Molecule) -> Result<Computed> {
+        self.0.compute(mol).await
     }
-}
 
-impl IpiListener {
-    /// Serve molecule computation reqeusts from `task`
-    pub async fn serve_channel(&self, task: &mut TaskReceiver) -> Result<()> {
-        info!("i-PI server: wait for external code connection and incoming molecule to compute ...");
-        let mut client_stream = self.accept().await?;
-        client_stream.wait_until_ready().await?;
-        debug!("client is ready now ...");
+    /// Poll STATUS of idle driver, which is declared dead if not answered
+    /// within `timeout`.
+    pub(crate) async fn heartbeat(&mut self, timeout: Duration) -> Result<()> {
+        self.0.heartbeat(timeout).await
+    }
 
+    /// Compute molecules received from shared `task` until the task channel
+    /// is closed. Idle driver not answering heartbeat is declared dead.
+    async fn serve_shared(mut self, task: SharedTaskReceiver, heartbeat: Option<Heartbeat>) -> Result<()> {
+        // NOTE: the client will be initialized when computing the first
+        // molecule
         loop {
             debug!("wait for new molecule to compute ...");
-            if let Some((mol, tx_out)) = task.recv().await {
+            // NOTE: the lock is released before computation, so other drivers
+            // can take the next molecule in the meantime
+            let received = match heartbeat {
+                None => task.lock().await.recv().await,
+                Some(heartbeat) => loop {
+                    tokio::select! {
+                        received = async { task.lock().await.recv().await } => break received,
+                        _ = tokio::time::sleep(heartbeat.interval) => self.0.heartbeat(heartbeat.timeout).await?,
+                    }
+                },
+            };
+            if let Some((mol, tx_out)) = received {
                 debug!("ask client to compute molecule {}", mol.title());
-                let computed = client_stream.compute_one(mol).await?;
+                let computed = self.compute_one(mol).await?;
                 match tx_out.send(computed) {
                     Ok(_) => {}
                     Err(_) => {}
                 }
             } else {
                 // task channel closed for some reason
-                client_stream.shutdown().await;
+                self.shutdown().await;
                 break;
             }
         }
@@ -176,27 +412,191 @@ impl IpiListener {
         Ok(())
     }
 }
-// 680b1817 ends here
 
-// [[file:../ipi.note::1b623d31][1b623d31]]
-macro_rules! process_client_stream_exit {
-    ($stream: expr) => {{
-        let (read, write) = $stream.split();
-        let _ = IpiServerStream::new(read, write).set_exit().await;
-    }};
+/// Serve molecule computation reqeusts from `task` using drivers from
+/// `incoming` connections, until the task channel is closed. Dead drivers
+/// are removed, and the remaining ones keep serving.
+async fn serve_drivers(
+    incoming: impl Stream<Item = Result<IpiStream>>,
+    task: TaskReceiver,
+    heartbeat: Option<Heartbeat>,
+) -> Result<()> {
+    let task = Arc::new(Mutex::new(task));
+    let closed = Arc::new(Notify::new());
+    futures::pin_mut!(incoming);
+
+    let mut ndrivers = 0;
+    loop {
+        tokio::select! {
+            stream = incoming.next() => {
+                let stream = stream.ok_or(format_err!("no more incoming drivers"))??;
+                ndrivers += 1;
+                info!("driver {ndrivers} connected");
+                let task = task.clone();
+                let closed = closed.clone();
+                tokio::spawn(async move {
+                    match stream.serve_shared(task, heartbeat).await {
+                        Ok(_) => closed.notify_one(),
+                        Err(err) => error!("driver {ndrivers} disconnected: {err:?}"),
+                    }
+                });
+            }
+            _ = closed.notified() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl IpiListener {
+    /// Serve molecule computation reqeusts from `task`. More drivers can be
+    /// connected at any time, and independent requests will be dispatched to
+    /// them in parallel.
+    pub async fn serve_channel(&self, task: TaskReceiver) -> Result<()> {
+        self.serve_channel_with_heartbeat(task, None).await
+    }
+
+    /// Serve molecule computation reqeusts from `task` as `serve_channel`,
+    /// polling idle drivers with STATUS as in `heartbeat`. Drivers not
+    /// answering in time are removed.
+    pub async fn serve_channel_with_heartbeat(&self, task: TaskReceiver, heartbeat: Option<Heartbeat>) -> Result<()> {
+        info!("i-PI server: wait for external code connection and incoming molecule to compute ...");
+        let incoming = futures::stream::unfold(self, |listener| async move { Some((listener.accept().await, listener)) });
+        serve_drivers(incoming, task, heartbeat).await
+    }
 }
 
+#[tokio::test]
+async fn test_serve_drivers() -> Result<()> {
+    use task::Task;
+
+    // a driver returning the number of atoms as energy
+    let natoms_as_energy = |mol: &Molecule| Computed {
+        energy: mol.natoms() as f64,
+        forces: vec![[0.0; 3]; mol.natoms()],
+        virial: [0.0; 9],
+        extra: String::new(),
+    };
+
+    // two drivers connected over in-memory pipes
+    let mut streams = vec![];
+    let mut drivers = vec![];
+    for _ in 0..2 {
+        let (server, driver) = tokio::io::duplex(1 << 16);
+        streams.push(Ok(IpiStream::from_stream(server)));
+        drivers.push(tokio::spawn(fake_driver(driver, natoms_as_energy)));
+    }
+    let incoming = futures::stream::iter(streams).chain(futures::stream::pending());
+    let (task_rx, task_tx) = Task::new().split();
+    let server = tokio::spawn(serve_drivers(incoming, task_rx, None));
+
+    let mol = Molecule::from_file("tests/files/quinone.cif")?;
+    let jobs = (0..4).map(|_| task_tx.remote_compute(mol.clone()));
+    let computed = futures::future::try_join_all(jobs).await?;
+    assert!(computed.iter().all(|c| c.energy == mol.natoms() as f64));
+
+    // drivers exit when task channel closed
+    drop(task_tx);
+    server.await??;
+    let mut ncomputed = 0;
+    for d in drivers {
+        ncomputed += d.await??;
+    }
+    assert_eq!(ncomputed, 4);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_heartbeat() -> Result<()> {
+    use task::Task;
+
+    // a driver answering STATUS while idle
+    let (server, driver) = tokio::io::duplex(1 << 16);
+    let responsive = tokio::spawn(async move {
+        let (read, write) = tokio::io::split(driver);
+        let mut read = FramedRead::new(read, codec::ServerCodec::default());
+        let mut write = FramedWrite::new(write, codec::ClientCodec::default());
+        let mut npolled = 0;
+        while let Some(msg) = read.next().await {
+            match msg? {
+                ServerMessage::Status => {
+                    npolled += 1;
+                    write.send(ClientMessage::Status(ClientStatus::NeedInit)).await?;
+                }
+                ServerMessage::Exit => break,
+                _ => {}
+            }
+        }
+        Ok::<_, std::io::Error>(npolled)
+    });
+    // a driver never answering, but keeping the connection open
+    let (dead_server, _dead_driver) = tokio::io::duplex(1 << 16);
+
+    let (task_rx, task_tx) = Task::new().split();
+    let task = Arc::new(Mutex::new(task_rx));
+    let heartbeat = Some(Heartbeat {
+        interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(100),
+    });
+    let dead = IpiStream::from_stream(dead_server).serve_shared(task.clone(), heartbeat);
+    assert!(tokio::time::timeout(Duration::from_secs(1), dead).await?.is_err());
+
+    let alive = tokio::spawn(IpiStream::from_stream(server).serve_shared(task, heartbeat));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    drop(task_tx);
+    alive.await??;
+    assert!(responsive.await?? >= 2);
+
+    Ok(())
+}
+// 680b1817 ends here
+
+// [[file:../ipi.note::1b623d31][1b623d31]]
 impl IpiStream {
-    async fn shutdown(&mut self) {
+    /// Send an exit message to driver to let it exit gracefully.
+    pub(crate) async fn shutdown(&mut self) {
         info!("sent exit message to client");
-        match self {
-            IpiStream::Tcp(s) => {
-                process_client_stream_exit!(s);
+        let _ = self.0.set_exit().await;
+    }
+}
+// 1b623d31 ends here
+
+// [[file:../ipi.note::6b1f0c3e][6b1f0c3e]]
+/// A fake driver over `stream` for tests, answering STATUS in the cycle of
+/// NEEDINIT, READY and HAVEDATA, and GETFORCE with results of `compute` for
+/// the molecule in last POSDATA. Return the number of computed molecules
+/// when EXIT received or connection closed.
+#[cfg(test)]
+pub(crate) async fn fake_driver<S>(stream: S, mut compute: impl FnMut(&Molecule) -> Computed) -> std::io::Result<usize>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (read, write) = tokio::io::split(stream);
+    let mut read = FramedRead::new(read, codec::ServerCodec::default());
+    let mut write = FramedWrite::new(write, codec::ClientCodec::default());
+    let mut status = ClientStatus::NeedInit;
+    let mut mol = None;
+    let mut ncomputed = 0;
+    while let Some(msg) = read.next().await {
+        match msg? {
+            ServerMessage::Status => write.send(ClientMessage::Status(status.clone())).await?,
+            ServerMessage::Init(_) => status = ClientStatus::Ready,
+            ServerMessage::PosData(m) => {
+                mol = Some(m);
+                status = ClientStatus::HaveData;
             }
-            IpiStream::Unix(s) => {
-                process_client_stream_exit!(s);
+            ServerMessage::GetForce => {
+                let mol = mol.as_ref().expect("GETFORCE before POSDATA");
+                write.send(ClientMessage::ForceReady(compute(mol))).await?;
+                ncomputed += 1;
+                status = ClientStatus::Ready;
             }
-        };
+            ServerMessage::Exit => break,
+        }
     }
+    Ok(ncomputed)
 }
-// 1b623d31 ends here
+// 6b1f0c3e ends here
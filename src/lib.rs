@@ -5,8 +5,6 @@ use gut::prelude::*;
 use gchemol::prelude::*;
 use gchemol::{Atom, Lattice, Molecule};
 
-use gosh_remote::LockFile;
-
 use std::path::{Path, PathBuf};
 // 45bd773d ends here
 
@@ -16,8 +14,18 @@ mod ipi;
 mod socket;
 
 pub mod cli;
-mod rest;
+pub mod hessian;
+mod lock;
+pub mod rest;
 mod task;
+mod tls;
+
+pub mod bias;
+pub mod bridge;
+pub mod composite;
+pub mod constraint;
+pub mod ensemble;
+pub mod recorder;
 // 2783ec3a ends here
 
 // [[file:../ipi.note::04b72e76][04b72e76]]
@@ -94,6 +102,101 @@ pub struct Computed {
 }
 // 04b72e76 ends here
 
+// [[file:../ipi.note::fef02747][fef02747]]
+impl Computed {
+    /// Return computed potential energy in eV.
+    pub fn energy(&self) -> f64 {
+        self.energy
+    }
+
+    /// Return computed forces in eV/Å.
+    pub fn forces(&self) -> &[[f64; 3]] {
+        &self.forces
+    }
+
+    /// Return computed virial tensor in eV.
+    pub fn virial(&self) -> [f64; 9] {
+        self.virial
+    }
+
+    /// Return extra data (usually in JSON format) from the client code.
+    pub fn extra(&self) -> &str {
+        &self.extra
+    }
+
+    /// Set potential energy in eV.
+    pub fn set_energy(&mut self, energy: f64) {
+        self.energy = energy;
+    }
+
+    /// Return a mutable reference to forces in eV/Å.
+    pub fn forces_mut(&mut self) -> &mut [[f64; 3]] {
+        &mut self.forces
+    }
+
+    /// Set extra data string.
+    pub fn set_extra(&mut self, extra: impl Into<String>) {
+        self.extra = extra.into();
+    }
+
+    /// Insert `key` with `value` into extra data as a JSON object. Non-JSON
+    /// extra string from client code will be kept in the `extra` key.
+    pub(crate) fn insert_extra(&mut self, key: &str, value: serde_json::Value) {
+        use serde_json::{Map, Value};
+
+        let mut map = match serde_json::from_str(&self.extra) {
+            Ok(Value::Object(map)) => map,
+            _ if self.extra.trim().is_empty() => Map::new(),
+            _ => {
+                let mut map = Map::new();
+                map.insert("extra".into(), self.extra.clone().into());
+                map
+            }
+        };
+        map.insert(key.into(), value);
+        self.extra = Value::Object(map).to_string();
+    }
+}
+// fef02747 ends here
+
+// [[file:../ipi.note::9b3e61d7][9b3e61d7]]
+/// Return cell vectors of periodic `mol` in rows, or None if not periodic.
+pub(crate) fn cell_vectors(mol: &Molecule) -> Option<[[f64; 3]; 3]> {
+    let vs = mol.get_lattice()?.vectors();
+    Some(std::array::from_fn(|i| [vs[i][0], vs[i][1], vs[i][2]]))
+}
+
+/// Wrap displacement `d` into its minimum image in periodic `cell`, by
+/// rounding its fractional coordinates. No wrapping if `cell` is None.
+///
+/// NOTE: this is exact unless the cell is strongly skewed.
+pub(crate) fn minimum_image(cell: Option<&[[f64; 3]; 3]>, d: [f64; 3]) -> [f64; 3] {
+    let [a, b, c] = match cell {
+        Some(cell) => *cell,
+        None => return d,
+    };
+    let dot = |x: [f64; 3], y: [f64; 3]| x[0] * y[0] + x[1] * y[1] + x[2] * y[2];
+    let cross = |x: [f64; 3], y: [f64; 3]| [x[1] * y[2] - x[2] * y[1], x[2] * y[0] - x[0] * y[2], x[0] * y[1] - x[1] * y[0]];
+    // fractional coordinates by Cramer's rule: d = f0 a + f1 b + f2 c
+    let det = dot(a, cross(b, c));
+    let f = [dot(d, cross(b, c)), dot(a, cross(d, c)), dot(a, cross(b, d))].map(|x| (x / det).round());
+    std::array::from_fn(|k| d[k] - f[0] * a[k] - f[1] * b[k] - f[2] * c[k])
+}
+
+#[test]
+fn test_minimum_image() {
+    use approx::*;
+
+    let cell = [[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [5.0, 0.0, 10.0]];
+    let d = minimum_image(Some(&cell), [9.0, -8.0, 9.5]);
+    let expected = [4.0, 2.0, -0.5];
+    for k in 0..3 {
+        assert_relative_eq!(d[k], expected[k], epsilon = 1e-10);
+    }
+    assert_eq!(minimum_image(None, [9.0, 0.0, 0.0]), [9.0, 0.0, 0.0]);
+}
+// 9b3e61d7 ends here
+
 // [[file:../ipi.note::242ad86a][242ad86a]]
 #[cfg(feature = "adhoc")]
 /// Docs for local mods
@@ -111,5 +214,14 @@ pub mod docs {
     export_doc!(ipi);
     export_doc!(rest);
     export_doc!(task);
+    export_doc!(hessian);
+    export_doc!(bridge);
+    export_doc!(bias);
+    export_doc!(constraint);
+    export_doc!(composite);
+    export_doc!(ensemble);
+    export_doc!(recorder);
+    export_doc!(lock);
+    export_doc!(tls);
 }
 // 242ad86a ends here
@@ -0,0 +1,180 @@
+// [[file:../ipi.note::9a41c6e2][9a41c6e2]]
+use super::*;
+
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+// 9a41c6e2 ends here
+
+// [[file:../ipi.note::d62f83b7][d62f83b7]]
+/// Connection metadata of a running proxy server, written in lock file in
+/// JSON format for client discovery.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// The address of REST service, like "127.0.0.1:45678", or
+    /// "unix:/path/to/socket" for unix domain socket
+    pub address: String,
+    /// The addresses of i-PI servers for drivers to connect: `host:port`,
+    /// the path of unix socket file, or `@name` in abstract namespace.
+    pub ipi: Vec<String>,
+    /// The process id of server
+    pub pid: u32,
+    /// The start time of server in seconds since UNIX epoch
+    pub started: u64,
+    /// The shared secret for accessing REST service
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl ServerInfo {
+    /// Create server info for current process.
+    pub fn new(address: impl std::fmt::Display, ipi: Vec<String>) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            address: address.to_string(),
+            ipi,
+            pid: std::process::id(),
+            started,
+            token: None,
+        }
+    }
+
+    /// Return true if the server process is still alive.
+    pub fn is_alive(&self) -> bool {
+        process_exists(self.pid)
+    }
+
+    /// Read server info from lock file in `path`, waiting at most `timeout`
+    /// seconds for it to appear. A stale lock file left by a dead server is
+    /// waited in the same way, as the server could be restarting, and will
+    /// be reported as an error on timeout.
+    pub fn from_lock_file(path: &Path, timeout: f64) -> Result<Self> {
+        let now = Instant::now();
+        loop {
+            match Self::read_lock_file(path) {
+                Ok(info) => return Ok(info),
+                Err(err) if now.elapsed().as_secs_f64() >= timeout => return Err(err),
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+    }
+
+    fn read_lock_file(path: &Path) -> Result<Self> {
+        ensure!(path.exists(), "lock file {path:?} not found");
+        let s = gut::fs::read_file(path)?;
+        let info: Self = serde_json::from_str(&s).with_context(|| format!("invalid lock file: {path:?}"))?;
+        ensure!(
+            info.is_alive(),
+            "stale lock file {path:?}: server process {} no longer exists",
+            info.pid
+        );
+        Ok(info)
+    }
+}
+
+/// Generate a random token as shared secret for REST service.
+pub(crate) fn generate_token() -> Result<String> {
+    use std::io::Read;
+
+    let mut buf = [0u8; 16];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut buf))
+        .context("failed to generate random token")?;
+    Ok(buf.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Return true if process `pid` exists.
+fn process_exists(pid: u32) -> bool {
+    if cfg!(target_os = "linux") {
+        Path::new(&format!("/proc/{pid}")).exists()
+    } else {
+        // NOTE: no portable way to check without libc, assuming alive
+        true
+    }
+}
+// d62f83b7 ends here
+
+// [[file:../ipi.note::1e5b0f94][1e5b0f94]]
+/// Lock file holding server info, which will be removed when dropped.
+#[derive(Debug)]
+pub struct ServerLock {
+    path: PathBuf,
+}
+
+impl ServerLock {
+    /// Write `info` into lock file in `path`. Refuse to overwrite the lock
+    /// file of another running server, but replace a stale one. The lock
+    /// file is only readable by its owner, as it may contain the token.
+    pub fn create(path: &Path, info: &ServerInfo) -> Result<Self> {
+        if path.exists() {
+            match gut::fs::read_file(path).ok().and_then(|s| serde_json::from_str::<ServerInfo>(&s).ok()) {
+                Some(old) if old.pid != info.pid && old.is_alive() => {
+                    bail!("lock file {path:?} is held by running server (pid {})", old.pid)
+                }
+                _ => warn!("replace stale lock file: {path:?}"),
+            }
+        }
+
+        // write into a temporary file first, so that clients never read a
+        // partial lock file
+        let json = serde_json::to_string_pretty(info)?;
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        let _ = std::fs::remove_file(&tmp);
+        let mut f = std::fs::OpenOptions::new();
+        f.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            f.mode(0o600);
+        }
+        f.open(&tmp)
+            .and_then(|mut f| std::io::Write::write_all(&mut f, json.as_bytes()))
+            .with_context(|| format!("failed to write {tmp:?}"))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("failed to create lock file {path:?}"))?;
+
+        Ok(Self { path: path.to_owned() })
+    }
+}
+
+impl Drop for ServerLock {
+    fn drop(&mut self) {
+        debug!("remove lock file: {:?}", self.path);
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn test_server_lock() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("gosh-ipi-test-{}.lock", std::process::id()));
+    let mut info = ServerInfo::new("127.0.0.1:12345", vec!["localhost:23456".into()]);
+    info.token = generate_token()?.into();
+    let lock = ServerLock::create(&path, &info)?;
+    let read = ServerInfo::from_lock_file(&path, 0.0)?;
+    assert_eq!(read.address, info.address);
+    assert_eq!(read.pid, std::process::id());
+    assert_eq!(read.token, info.token);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(path.metadata()?.permissions().mode() & 0o777, 0o600);
+    }
+    drop(lock);
+    assert!(!path.exists());
+
+    // lock file left by dead process
+    if cfg!(target_os = "linux") {
+        let stale = ServerInfo {
+            pid: u32::MAX,
+            ..info.clone()
+        };
+        gut::fs::write_to_file(&path, &serde_json::to_string(&stale)?)?;
+        assert!(ServerInfo::from_lock_file(&path, 0.0).is_err());
+        let _lock = ServerLock::create(&path, &info)?;
+        assert!(ServerInfo::from_lock_file(&path, 0.0).is_ok());
+    }
+
+    Ok(())
+}
+// 1e5b0f94 ends here
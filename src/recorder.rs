@@ -0,0 +1,180 @@
+// [[file:../ipi.note::5f08b2c6][5f08b2c6]]
+use super::*;
+
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+// 5f08b2c6 ends here
+
+// [[file:../ipi.note::93d1a7e4][93d1a7e4]]
+/// Options for recording computed structures
+#[derive(Debug, Clone)]
+pub struct RecorderOptions {
+    /// The extended XYZ file for appending structures
+    pub path: PathBuf,
+    /// Rotate the file when its size exceeds this number of bytes. The
+    /// previous file will be renamed with a numbered suffix.
+    pub max_size: Option<u64>,
+    /// Skip structures with the same species and positions as any recorded
+    /// structure, after positions and cell are quantized on a grid with
+    /// this spacing (in Å).
+    ///
+    /// NOTE: this is not a distance tolerance: two structures could differ
+    /// by much less than the spacing but fall on different grid points.
+    pub dedup: Option<f64>,
+}
+
+/// Record each computed structure as training data in extended XYZ format.
+#[derive(Debug)]
+pub struct Recorder {
+    options: RecorderOptions,
+    /// Geometry fingerprints of recorded structures
+    seen: HashSet<u64>,
+    nrecorded: usize,
+}
+// 93d1a7e4 ends here
+
+// [[file:../ipi.note::4a6e0f17][4a6e0f17]]
+/// Return geometry fingerprint of `mol`, with positions and cell vectors
+/// rounded to the nearest point on a grid with `spacing`.
+fn fingerprint(mol: &Molecule, spacing: f64) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    for (s, p) in mol.symbols().zip(mol.positions()) {
+        s.hash(&mut hasher);
+        for x in p {
+            ((x / spacing).round() as i64).hash(&mut hasher);
+        }
+    }
+    if let Some(lat) = mol.get_lattice() {
+        for v in lat.vectors() {
+            for k in 0..3 {
+                ((v[k] / spacing).round() as i64).hash(&mut hasher);
+            }
+        }
+    }
+    hasher.finish()
+}
+
+/// Format scalar `value` in extra data as extended XYZ value.
+fn format_extxyz_value(value: &serde_json::Value) -> Option<String> {
+    use serde_json::Value;
+
+    match value {
+        Value::Bool(b) => Some(if *b { "T".into() } else { "F".into() }),
+        Value::Number(x) => Some(x.to_string()),
+        Value::String(s) => Some(format!("{:?}", s)),
+        Value::Array(xs) => {
+            let xs: Option<Vec<_>> = xs.iter().map(|x| x.as_f64().map(|x| x.to_string())).collect();
+            xs.map(|xs| format!("\"{}\"", xs.join(" ")))
+        }
+        _ => None,
+    }
+}
+
+/// Format `mol` with `computed` results in extended XYZ format.
+fn format_extxyz(mol: &Molecule, computed: &Computed) -> String {
+    let mut comment = vec![];
+    match mol.get_lattice() {
+        Some(lat) => {
+            let vs: Vec<_> = lat.vectors().iter().flat_map(|v| [v[0], v[1], v[2]]).map(|x| x.to_string()).collect();
+            comment.push(format!("Lattice=\"{}\"", vs.join(" ")));
+            comment.push("pbc=\"T T T\"".into());
+        }
+        None => comment.push("pbc=\"F F F\"".into()),
+    }
+    comment.push("Properties=species:S:1:pos:R:3:forces:R:3".into());
+    comment.push(format!("energy={}", computed.energy));
+    let virial: Vec<_> = computed.virial.iter().map(|x| x.to_string()).collect();
+    comment.push(format!("virial=\"{}\"", virial.join(" ")));
+    // scalar or numeric array fields in extra data
+    if let Ok(serde_json::Value::Object(map)) = serde_json::from_str(&computed.extra) {
+        for (k, v) in map.iter() {
+            if let Some(v) = format_extxyz_value(v) {
+                comment.push(format!("{k}={v}"));
+            }
+        }
+    }
+
+    let mut lines = vec![mol.natoms().to_string(), comment.join(" ")];
+    for ((s, [x, y, z]), [fx, fy, fz]) in mol.symbols().zip(mol.positions()).zip(&computed.forces) {
+        lines.push(format!("{s:3} {x:18.8} {y:18.8} {z:18.8} {fx:18.8} {fy:18.8} {fz:18.8}"));
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+#[test]
+fn test_format_extxyz() {
+    let mol = Molecule::from_file("tests/files/quinone.cif").unwrap();
+    let mut computed = Computed {
+        energy: -1.5,
+        forces: vec![[0.0; 3]; mol.natoms()],
+        virial: [0.0; 9],
+        extra: String::new(),
+    };
+    computed.insert_extra("bias_energy", 0.1.into());
+    let s = format_extxyz(&mol, &computed);
+    let lines: Vec<_> = s.lines().collect();
+    assert_eq!(lines.len(), mol.natoms() + 2);
+    assert!(lines[1].starts_with("Lattice="));
+    assert!(lines[1].contains("energy=-1.5"));
+    assert!(lines[1].contains("bias_energy=0.1"));
+}
+// 4a6e0f17 ends here
+
+// [[file:../ipi.note::c70b5d28][c70b5d28]]
+impl Recorder {
+    pub fn new(options: RecorderOptions) -> Self {
+        Self {
+            options,
+            seen: HashSet::new(),
+            nrecorded: 0,
+        }
+    }
+
+    /// Rename current file with a numbered suffix if it is too large.
+    fn rotate(&self) -> Result<()> {
+        let path = &self.options.path;
+        if let Some(max_size) = self.options.max_size {
+            if path.exists() && path.metadata()?.len() >= max_size {
+                let rotated = (1..)
+                    .map(|i| PathBuf::from(format!("{}.{i}", path.display())))
+                    .find(|p| !p.exists())
+                    .expect("rotated file name");
+                info!("rotate {path:?} to {rotated:?}");
+                std::fs::rename(path, rotated)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Append `mol` with `computed` results into the dataset file.
+    pub fn record(&mut self, mol: &Molecule, computed: &Computed) -> Result<()> {
+        if let Some(spacing) = self.options.dedup {
+            if !self.seen.insert(fingerprint(mol, spacing)) {
+                debug!("skip duplicated structure {}", mol.title());
+                return Ok(());
+            }
+        }
+
+        self.rotate()?;
+        let path = &self.options.path;
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("open {path:?} for recording"))?;
+        f.write_all(format_extxyz(mol, computed).as_bytes())?;
+        self.nrecorded += 1;
+        debug!("{} structures recorded in {path:?}", self.nrecorded);
+
+        Ok(())
+    }
+}
+
+impl bridge::Transit for Recorder {
+    fn transit(&mut self, mol: &Molecule, computed: &mut Computed) -> Result<()> {
+        self.record(mol, computed)
+    }
+}
+// c70b5d28 ends here
@@ -5,56 +5,253 @@ use socket::IpiListener;
 use task::{Task, TaskReceiver, TaskSender};
 
 use gosh_model::ModelProperties;
+use serde::Deserialize;
 // 3d2c01c2 ends here
 
 // [[file:../ipi.note::aa8d1d68][aa8d1d68]]
+mod binary;
 mod client;
 mod server;
+mod stream;
 // aa8d1d68 ends here
 
 // [[file:../ipi.note::285a8db0][285a8db0]]
-pub use client::Client;
+pub use client::{Client, RetryOptions};
+pub use lock::ServerInfo;
+pub use stream::StreamSession;
+
+/// Virial and extra data in JSON response, which are not kept in
+/// `ModelProperties`.
+#[derive(Deserialize)]
+struct ComputedExtra {
+    #[serde(default)]
+    virial: [f64; 9],
+    #[serde(default)]
+    extra: Option<serde_json::Value>,
+}
+
+/// Decode computed results from JSON response of remote server.
+fn decode_computed_json(x: &[u8]) -> Result<Computed> {
+    let value: serde_json::Value = serde_json::from_slice(x).with_context(|| format!("invalid json: {x:?}"))?;
+    let mp: ModelProperties = serde_json::from_value(value.clone())?;
+    let ComputedExtra { virial, extra } = serde_json::from_value(value)?;
+    let extra = match extra {
+        None => String::new(),
+        Some(serde_json::Value::String(s)) => s,
+        Some(v) => v.to_string(),
+    };
+    Ok(Computed {
+        energy: mp.get_energy().ok_or(format_err!("no energy in response"))?,
+        forces: mp.get_forces().cloned().ok_or(format_err!("no forces in response"))?,
+        virial,
+        extra,
+    })
+}
 
 impl Client {
-    #[tokio::main]
-    /// Request remote server compute `mol` using external code in i-PI protocol
-    pub async fn compute_molecule(&self, mol: &Molecule) -> Result<ModelProperties> {
+    /// Request remote server compute `mol` using external code in i-PI
+    /// protocol, returning all computed results including virial and
+    /// extra data from external code.
+    pub async fn compute_results_async(&self, mol: &Molecule) -> Result<Computed> {
         info!("Request server to compute molecule {}", mol.title());
-        let x = self.post("mol", &mol).await?;
-        let mol = serde_json::from_str(&x).with_context(|| format!("invalid json str: {x:?}"))?;
-        Ok(mol)
+        let computed = if self.binary {
+            let x = self.post_bytes("mol", binary::BINARY_CONTENT_TYPE, binary::encode_molecule(mol)).await?;
+            binary::decode_computed(x)?
+        } else {
+            let x = self.post_bytes("mol", binary::JSON_CONTENT_TYPE, serde_json::to_vec(mol)?).await?;
+            decode_computed_json(&x)?
+        };
+        ensure!(
+            computed.forces.len() == mol.natoms(),
+            "server returned forces for a different number of atoms"
+        );
+        Ok(computed)
+    }
+
+    /// Request remote server compute `mol` using external code in i-PI
+    /// protocol. The returned properties carry `mol` as submitted, with
+    /// all its metadata intact.
+    pub async fn compute_molecule_async(&self, mol: &Molecule) -> Result<ModelProperties> {
+        let computed = self.compute_results_async(mol).await?;
+        let mut mp = ModelProperties::default();
+        mp.set_energy(computed.energy);
+        mp.set_forces(computed.forces);
+        mp.set_molecule(mol.clone());
+        Ok(mp)
     }
+
+    /// Request remote server compute all molecules in `mols` concurrently,
+    /// returning results in the same order.
+    pub async fn compute_molecules_async(&self, mols: &[Molecule]) -> Result<Vec<ModelProperties>> {
+        let jobs = mols.iter().map(|mol| self.compute_molecule_async(mol));
+        futures::future::try_join_all(jobs).await
+    }
+
+    /// Blocking version of `compute_molecule_async`, which can not be
+    /// called inside an async runtime.
+    pub fn compute_molecule(&self, mol: &Molecule) -> Result<ModelProperties> {
+        self.block_on(self.compute_molecule_async(mol))?
+    }
+
+    /// Blocking version of `compute_molecules_async`, which can not be
+    /// called inside an async runtime.
+    pub fn compute_molecules(&self, mols: &[Molecule]) -> Result<Vec<ModelProperties>> {
+        self.block_on(self.compute_molecules_async(mols))?
+    }
+}
+
+#[test]
+fn test_decode_computed_json() -> Result<()> {
+    let x = br#"{"energy": -1.0, "forces": [[0.1, 0.2, 0.3]], "virial": [0.5, 0, 0, 0, 0.5, 0, 0, 0, 0.5], "extra": {"bias_energy": 0.1}}"#;
+    let computed = decode_computed_json(x)?;
+    assert_eq!(computed.energy, -1.0);
+    assert_eq!(computed.forces, vec![[0.1, 0.2, 0.3]]);
+    assert_eq!(computed.virial[4], 0.5);
+    assert!(computed.extra.contains("bias_energy"));
+
+    // virial and extra data are optional
+    let computed = decode_computed_json(br#"{"energy": -1.0, "forces": [[0.1, 0.2, 0.3]]}"#)?;
+    assert_eq!(computed.virial, [0.0; 9]);
+    assert!(computed.extra.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_blocking_in_runtime() {
+    let client = Client::connect("localhost:1");
+    let mol = Molecule::from_file("tests/files/quinone.cif").unwrap();
+    // report error instead of panicking
+    assert!(client.compute_molecule(&mol).is_err());
 }
 // 285a8db0 ends here
 
 // [[file:../ipi.note::389c909a][389c909a]]
-use socket::Socket;
-
 /// Server side for proxying i-PI computation requests to external code
 pub struct Server;
 
+/// Options for proxy server
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// The i-PI server for driver to connect. Ignored for composite model.
+    pub ipi: composite::DriverConfig,
+    /// Require a token generated at startup for accessing REST service
+    pub auth: bool,
+    /// Serve REST service on unix domain socket at this path, instead of a
+    /// free TCP port
+    pub rest_socket: Option<PathBuf>,
+    /// External bias potential added on top of computed energy and forces
+    pub bias: Option<bias::BiasPotential>,
+    /// Constraints imposed on computed forces, e.g. for frozen atoms
+    pub constraints: Option<constraint::Constraints>,
+    /// Combine several drivers into one model, instead of using a single
+    /// driver
+    pub composite: Option<composite::CompositeConfig>,
+    /// Broadcast each molecule to all drivers in committee, instead of
+    /// dispatching it to one driver
+    pub ensemble: Option<ensemble::EnsembleOptions>,
+    /// Record each computed structure into a dataset file
+    pub recorder: Option<recorder::RecorderOptions>,
+}
+
 impl Server {
     /// Wait for incoming task and forward computation to external code in i-PI protocol
-    async fn serve_incoming_task(mut task: TaskReceiver) {
-        // FIXME: remove unwrap, and allow custom port
-        let mut ipi_server = Socket::bind("localhost", 12345, false).await.unwrap();
-        // if let Err(err) = task.compute_molecule_with(&mut ipi_server).await {
-        if let Err(err) = ipi_server.serve_channel(&mut task).await {
+    async fn serve_incoming_task(
+        ipi_server: IpiListener,
+        task: TaskReceiver,
+        ensemble: Option<ensemble::EnsembleOptions>,
+        heartbeat: Option<ipi::Heartbeat>,
+    ) -> Result<()> {
+        let ret = match ensemble {
+            Some(options) => ipi_server.serve_channel_ensemble(task, &options, heartbeat).await,
+            None => ipi_server.serve_channel_with_heartbeat(task, heartbeat).await,
+        };
+        if let Err(err) = &ret {
             error!("{err:?}");
         }
+        ret
     }
 
     #[tokio::main]
     /// Enter point for command line usage
-    pub async fn enter_main(lock_file: &Path) -> Result<()> {
-        let addr = socket::get_free_tcp_address().ok_or(format_err!("no free tcp addr"))?;
-        println!("listening on {addr:?}");
-        let _lock = LockFile::new(lock_file, addr);
-
-        let (task_rx, task_tx) = Task::new().split();
-        let h1 = tokio::spawn(async move { Self::run_restful(addr, task_tx).await });
-        let h2 = tokio::spawn(async move { Self::serve_incoming_task(task_rx).await });
-        tokio::try_join!(h1, h2)?;
+    pub async fn enter_main(lock_file: &Path, options: ServerOptions) -> Result<()> {
+        Self::serve(lock_file, options).await
+    }
+
+    /// Serve computation requests in the runtime of host application, until
+    /// Ctrl+C or SIGTERM received.
+    pub async fn serve(lock_file: &Path, options: ServerOptions) -> Result<()> {
+        Self::serve_with_shutdown(lock_file, options, server::shutdown_signal()).await
+    }
+
+    /// Serve computation requests in the runtime of host application, until
+    /// `shutdown` completes.
+    pub async fn serve_with_shutdown(
+        lock_file: &Path,
+        options: ServerOptions,
+        shutdown: impl std::future::Future<Output = ()>,
+    ) -> Result<()> {
+        ensure!(
+            options.composite.is_none() || options.ensemble.is_none(),
+            "composite model and committee models can not be used together"
+        );
+        let listener = match &options.rest_socket {
+            Some(path) => {
+                let (listener, file) = socket::bind_private_unix_socket(path)?;
+                server::RestListener::Unix(listener, file, path.to_owned())
+            }
+            None => {
+                let addr = socket::get_free_tcp_address().ok_or(format_err!("no free tcp addr"))?;
+                server::RestListener::Tcp(addr)
+            }
+        };
+        let addr = listener.address();
+        info!("listening on {addr}");
+        let token = if options.auth { Some(lock::generate_token()?) } else { None };
+        let server_info = |ipi| lock::ServerInfo {
+            token: token.clone(),
+            ..lock::ServerInfo::new(&addr, ipi)
+        };
+
+        if let Some(config) = options.composite.clone() {
+            let ipi = config.drivers.values().map(|d| d.describe()).collect();
+            let _lock = lock::ServerLock::create(lock_file, &server_info(ipi))?;
+            let model = composite::CompositeModel::start(config).await?;
+            let state = server::State::new(model.into(), &options, token);
+            Self::run_restful(listener, state, shutdown).await;
+        } else {
+            let ipi = &options.ipi;
+            let copies = options.ensemble.as_ref().map_or(1, |e| e.nmodels);
+            let ipi_server = ipi.listen(copies).await?;
+            let _lock = lock::ServerLock::create(lock_file, &server_info(vec![ipi.describe()]))?;
+            let (task_rx, task_tx) = Task::new().split();
+            let state = server::State::new(task_tx.into(), &options, token);
+            let ensemble = options.ensemble.clone();
+            let heartbeat = ipi.heartbeat();
+            // NOTE: the i-PI server stops early only if drivers failed, then
+            // the restful service should be stopped too, instead of
+            // accepting requests that can never be computed.
+            let (stopped_tx, stopped_rx) = tokio::sync::oneshot::channel();
+            let mut h = tokio::spawn(async move {
+                let ret = Self::serve_incoming_task(ipi_server, task_rx, ensemble, heartbeat).await;
+                let _ = stopped_tx.send(());
+                ret
+            });
+            let shutdown = async {
+                tokio::select! {
+                    _ = shutdown => {},
+                    _ = stopped_rx => warn!("i-PI server stopped, shutting down restful service ..."),
+                }
+            };
+            Self::run_restful(listener, state, shutdown).await;
+            // the task channel is closed now, wait a while for drivers to
+            // exit, and stop accepting new drivers
+            let timeout = tokio::time::sleep(std::time::Duration::from_secs(1));
+            tokio::select! {
+                ret = &mut h => ret?.context("i-PI server failed")?,
+                _ = timeout => h.abort(),
+            }
+        }
         Ok(())
     }
 }
@@ -0,0 +1,165 @@
+// [[file:../../ipi.note::c3f5a8e0][c3f5a8e0]]
+use super::*;
+
+use bytes::{Buf, BufMut};
+use bytes::{Bytes, BytesMut};
+// c3f5a8e0 ends here
+
+// [[file:../../ipi.note::41b7d9a6][41b7d9a6]]
+/// The media type for compact binary payloads
+pub(crate) const BINARY_CONTENT_TYPE: &str = "application/x-gosh-ipi";
+/// The media type for JSON payloads, the default for debugging
+pub(crate) const JSON_CONTENT_TYPE: &str = "application/json";
+
+const MOLECULE_MAGIC: &[u8; 4] = b"GIM1";
+const COMPUTED_MAGIC: &[u8; 4] = b"GIC1";
+
+/// Return true if `content_type` (from Content-Type or Accept header) asks
+/// for binary payload.
+pub(crate) fn is_binary(content_type: &str) -> bool {
+    content_type.split(',').any(|t| t.trim().starts_with(BINARY_CONTENT_TYPE))
+}
+
+fn ensure_remaining(src: &Bytes, n: usize) -> Result<()> {
+    ensure!(src.remaining() >= n, "truncated binary payload");
+    Ok(())
+}
+
+fn put_str(dst: &mut BytesMut, s: &str) {
+    dst.put_u32_le(s.len() as u32);
+    dst.put_slice(s.as_bytes());
+}
+
+fn get_str(src: &mut Bytes) -> Result<String> {
+    ensure_remaining(src, 4)?;
+    let n = src.get_u32_le() as usize;
+    ensure_remaining(src, n)?;
+    let s = String::from_utf8(src.split_to(n).to_vec())?;
+    Ok(s)
+}
+
+fn get_f64s(src: &mut Bytes, n: usize) -> Result<Vec<f64>> {
+    ensure_remaining(src, n * 8)?;
+    Ok((0..n).map(|_| src.get_f64_le()).collect())
+}
+// 41b7d9a6 ends here
+
+// [[file:../../ipi.note::8f2c6d13][8f2c6d13]]
+/// Encode species, positions and lattice of `mol` in little endian:
+///
+/// magic, title, natoms (u32), symbols, positions (3N f64), has lattice
+/// (u8), lattice vectors (9 f64)
+pub(crate) fn encode_molecule(mol: &Molecule) -> Bytes {
+    let natoms = mol.natoms();
+    let mut dst = BytesMut::with_capacity(64 + natoms * 28);
+    dst.put_slice(MOLECULE_MAGIC);
+    put_str(&mut dst, &mol.title());
+    dst.put_u32_le(natoms as u32);
+    for s in mol.symbols() {
+        put_str(&mut dst, s);
+    }
+    for p in mol.positions() {
+        p.iter().for_each(|x| dst.put_f64_le(*x));
+    }
+    match mol.get_lattice() {
+        Some(lat) => {
+            dst.put_u8(1);
+            for v in lat.vectors() {
+                (0..3).for_each(|k| dst.put_f64_le(v[k]));
+            }
+        }
+        None => dst.put_u8(0),
+    }
+    dst.freeze()
+}
+
+/// Decode molecule encoded by `encode_molecule`.
+pub(crate) fn decode_molecule(mut src: Bytes) -> Result<Molecule> {
+    ensure_remaining(&src, 4)?;
+    ensure!(&src.split_to(4)[..] == MOLECULE_MAGIC, "invalid binary molecule");
+    let title = get_str(&mut src)?;
+    ensure_remaining(&src, 4)?;
+    let natoms = src.get_u32_le() as usize;
+    let symbols = (0..natoms).map(|_| get_str(&mut src)).collect::<Result<Vec<_>>>()?;
+    let xs = get_f64s(&mut src, 3 * natoms)?;
+    let atoms = symbols
+        .iter()
+        .zip(xs.chunks(3))
+        .map(|(s, p)| Atom::new(s.as_str(), [p[0], p[1], p[2]]));
+    let mut mol = Molecule::from_atoms(atoms);
+    mol.set_title(&title);
+
+    ensure_remaining(&src, 1)?;
+    if src.get_u8() == 1 {
+        let vs = get_f64s(&mut src, 9)?;
+        let lattice = [[vs[0], vs[1], vs[2]], [vs[3], vs[4], vs[5]], [vs[6], vs[7], vs[8]]];
+        mol.set_lattice(Lattice::new(lattice));
+    }
+    Ok(mol)
+}
+
+/// Encode `computed` results in little endian:
+///
+/// magic, energy (f64), natoms (u32), forces (3N f64), virial (9 f64),
+/// extra data
+pub(crate) fn encode_computed(computed: &Computed) -> Bytes {
+    let natoms = computed.forces.len();
+    let mut dst = BytesMut::with_capacity(96 + natoms * 24 + computed.extra.len());
+    dst.put_slice(COMPUTED_MAGIC);
+    dst.put_f64_le(computed.energy);
+    dst.put_u32_le(natoms as u32);
+    for f in computed.forces.iter() {
+        f.iter().for_each(|x| dst.put_f64_le(*x));
+    }
+    computed.virial.iter().for_each(|x| dst.put_f64_le(*x));
+    put_str(&mut dst, &computed.extra);
+    dst.freeze()
+}
+
+/// Decode computed results encoded by `encode_computed`.
+pub(crate) fn decode_computed(mut src: Bytes) -> Result<Computed> {
+    ensure_remaining(&src, 4)?;
+    ensure!(&src.split_to(4)[..] == COMPUTED_MAGIC, "invalid binary computed results");
+    ensure_remaining(&src, 12)?;
+    let energy = src.get_f64_le();
+    let natoms = src.get_u32_le() as usize;
+    let forces = get_f64s(&mut src, 3 * natoms)?.chunks(3).map(|f| [f[0], f[1], f[2]]).collect();
+    let vs = get_f64s(&mut src, 9)?;
+    let virial = std::array::from_fn(|i| vs[i]);
+    let extra = get_str(&mut src)?;
+    Ok(Computed {
+        energy,
+        forces,
+        virial,
+        extra,
+    })
+}
+
+#[test]
+fn test_binary_payloads() {
+    let mol = Molecule::from_file("tests/files/quinone.cif").unwrap();
+    let mol2 = decode_molecule(encode_molecule(&mol)).unwrap();
+    assert_eq!(mol2.natoms(), mol.natoms());
+    assert_eq!(mol2.title(), mol.title());
+    assert!(mol2.symbols().eq(mol.symbols()));
+    assert!(mol2.positions().eq(mol.positions()));
+    assert_eq!(mol2.get_lattice().unwrap().vectors(), mol.get_lattice().unwrap().vectors());
+
+    let computed = Computed {
+        energy: -1.5,
+        forces: vec![[0.1, 0.2, 0.3]; mol.natoms()],
+        virial: [0.5; 9],
+        extra: r#"{"bias_energy": 0.1}"#.into(),
+    };
+    let encoded = encode_computed(&computed);
+    let c = decode_computed(encoded.clone()).unwrap();
+    assert_eq!(c.energy, computed.energy);
+    assert_eq!(c.forces, computed.forces);
+    assert_eq!(c.virial, computed.virial);
+    assert_eq!(c.extra, computed.extra);
+
+    // truncated payload
+    assert!(decode_computed(encoded.slice(..encoded.len() - 1)).is_err());
+    assert!(decode_molecule(encoded).is_err());
+}
+// 8f2c6d13 ends here
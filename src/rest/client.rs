@@ -4,30 +4,435 @@ use reqwest;
 // 8bb618e6 ends here
 
 // [[file:../../ipi.note::d2c8de54][d2c8de54]]
+/// One remote service the client can talk to
+#[derive(Debug, Clone)]
+pub(super) struct Endpoint {
+    pub(super) service_uri: String,
+    pub(super) token: Option<String>,
+    /// The socket file for service on unix domain socket
+    pub(super) unix: Option<PathBuf>,
+    /// The lock file where this endpoint was found, for re-resolving the
+    /// address of restarted server
+    lock_file: Option<PathBuf>,
+}
+
+impl Endpoint {
+    /// Parse address like "localhost:12345", or "unix:/path/to/socket" for
+    /// service on unix domain socket
+    fn new(address: impl std::fmt::Display) -> Self {
+        let address = address.to_string();
+        let (service_uri, unix) = match address.strip_prefix("unix:") {
+            Some(path) => ("http://localhost".to_owned(), Some(path.into())),
+            None => (format!("http://{}", address), None),
+        };
+        Self {
+            service_uri,
+            token: None,
+            unix,
+            lock_file: None,
+        }
+    }
+
+    /// Create endpoint from server `info` found in `lock_file`.
+    fn from_server_info(info: &lock::ServerInfo, lock_file: Option<&Path>) -> Self {
+        Self {
+            token: info.token.clone(),
+            lock_file: lock_file.map(|p| p.to_owned()),
+            ..Self::new(&info.address)
+        }
+    }
+
+    /// Re-read the lock file if any, as the server could be restarted on a
+    /// new address with a new token. The current endpoint is kept if the
+    /// lock file is not readable for now.
+    pub(super) fn resolve(&self) -> Self {
+        match &self.lock_file {
+            Some(path) => match lock::ServerInfo::from_lock_file(path, 0.0) {
+                Ok(info) => Self::from_server_info(&info, Some(path)),
+                Err(err) => {
+                    debug!("keep endpoint {}: {err:?}", self.describe());
+                    self.clone()
+                }
+            },
+            None => self.clone(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.unix {
+            Some(path) => format!("unix:{}", path.display()),
+            None => self.service_uri.clone(),
+        }
+    }
+}
+
+/// Options for retrying failed requests with exponential backoff
+#[derive(Debug, Clone)]
+pub struct RetryOptions {
+    /// Max number of retries after the first attempt
+    pub max_retries: usize,
+    /// The delay in seconds before the first retry, which will be doubled
+    /// for each following retry
+    pub initial_delay: f64,
+    /// The max delay in seconds between retries
+    pub max_delay: f64,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_delay: 0.5,
+            max_delay: 30.0,
+        }
+    }
+}
+
 /// Client for remote execution
 pub struct Client {
     client: reqwest::Client,
-    service_uri: String,
+    /// Servers in order of preference. Failed requests will be retried on
+    /// next server.
+    pub(super) endpoints: Vec<Endpoint>,
+    /// Use compact binary payloads instead of JSON
+    pub(super) binary: bool,
+    retry: RetryOptions,
+    /// The runtime for blocking calls, created on first use
+    runtime: std::sync::OnceLock<tokio::runtime::Runtime>,
 }
 
 impl Client {
-    /// Connect to remote service using address like "localhost:12345"
+    /// Connect to remote service using address like "localhost:12345", or
+    /// "unix:/path/to/socket" for service on unix domain socket
     pub fn connect(address: impl std::fmt::Display) -> Self {
+        Self::connect_any([address])
+    }
+
+    /// Connect to any of remote services in `addresses`, failing over to
+    /// the next one when a request fails.
+    pub fn connect_any<T: std::fmt::Display>(addresses: impl IntoIterator<Item = T>) -> Self {
         // by the default there is no timeout
         let client = reqwest::Client::builder().build().expect("reqwest client");
-        let service_uri = format!("http://{}", address);
-        Self { client, service_uri }
+        Self {
+            client,
+            endpoints: addresses.into_iter().map(Endpoint::new).collect(),
+            binary: false,
+            retry: RetryOptions::default(),
+            runtime: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// Set the token for authentication with remote service.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        let token = token.into();
+        for ep in self.endpoints.iter_mut() {
+            ep.token = Some(token.clone());
+        }
+        self
+    }
+
+    /// Exchange compact binary payloads with remote service, instead of
+    /// JSON which is the default for easy debugging.
+    pub fn with_binary(mut self, binary: bool) -> Self {
+        self.binary = binary;
+        self
+    }
+
+    /// Run `future` to completion for blocking calls, reusing the same
+    /// runtime owned by client.
+    pub(super) fn block_on<F: std::future::Future>(&self, future: F) -> Result<F::Output> {
+        ensure!(
+            tokio::runtime::Handle::try_current().is_err(),
+            "blocking call inside async runtime, use the async method instead"
+        );
+        let rt = self.runtime.get_or_init(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("tokio runtime")
+        });
+        Ok(rt.block_on(future))
+    }
+
+    /// Set options for retrying failed requests.
+    pub fn with_retry(mut self, retry: RetryOptions) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Connect to remote service found in lock file, with token if any.
+    pub fn from_server_info(info: &lock::ServerInfo) -> Self {
+        Self::from_server_infos(std::slice::from_ref(info))
+    }
+
+    /// Connect to any of remote services found in lock files, failing over
+    /// to the next one when a request fails.
+    pub fn from_server_infos(infos: &[lock::ServerInfo]) -> Self {
+        let mut client = Self::connect_any(infos.iter().map(|info| &info.address));
+        for (ep, info) in client.endpoints.iter_mut().zip(infos) {
+            ep.token = info.token.clone();
+        }
+        client
+    }
+
+    /// Connect to any of remote services found in `lock_files`, waiting at
+    /// most `wait` seconds for each to appear. Unlike `from_server_infos`,
+    /// the lock files will be read again before retrying failed requests,
+    /// so that a restarted server can be found.
+    pub fn from_lock_files<P: AsRef<Path>>(lock_files: &[P], wait: f64) -> Result<Self> {
+        let mut client = Self::connect_any(Vec::<String>::new());
+        for path in lock_files {
+            let path = path.as_ref();
+            match lock::ServerInfo::from_lock_file(path, wait) {
+                Ok(info) => client.endpoints.push(Endpoint::from_server_info(&info, Some(path))),
+                Err(err) => warn!("{err:?}"),
+            }
+        }
+        let paths: Vec<_> = lock_files.iter().map(|p| p.as_ref()).collect();
+        ensure!(!client.endpoints.is_empty(), "no running server found in lock files: {paths:?}");
+        Ok(client)
     }
 }
+
+#[test]
+fn test_endpoint_resolve() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("gosh-ipi-resolve-{}.lock", std::process::id()));
+    let info = lock::ServerInfo::new("127.0.0.1:12345", vec![]);
+    let lock = lock::ServerLock::create(&path, &info)?;
+    let client = Client::from_lock_files(&[&path], 0.0)?;
+    drop(lock);
+
+    // server restarted on another address with token
+    let mut info = lock::ServerInfo::new("unix:/tmp/restarted.sock", vec![]);
+    info.token = Some("secret".into());
+    let lock = lock::ServerLock::create(&path, &info)?;
+    let ep = client.endpoints[0].resolve();
+    assert_eq!(ep.unix.as_deref(), Some(Path::new("/tmp/restarted.sock")));
+    assert_eq!(ep.token.as_deref(), Some("secret"));
+    drop(lock);
+
+    // the last known address is kept if the lock file is gone
+    let ep = ep.resolve();
+    assert_eq!(ep.describe(), "unix:/tmp/restarted.sock");
+
+    Ok(())
+}
 // d2c8de54 ends here
 
 // [[file:../../ipi.note::743b32f9][743b32f9]]
+use bytes::Bytes;
+
+/// The header for identifying retried requests, so that server will not
+/// compute the same request twice.
+pub(super) const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Failure in one attempt of request
+enum RequestError {
+    /// Connection failure, timeout or server temporarily unavailable, which
+    /// is worth retrying
+    Transient(Error),
+    /// Rejected request or failed computation
+    Fatal(Error),
+}
+
+fn is_transient_status(status: u16) -> bool {
+    matches!(status, 408 | 429 | 502 | 503 | 504)
+}
+
+/// Classify error in sending request. Only malformed requests are fatal:
+/// failures on connection, timeout, or reset body are worth retrying, since
+/// retried requests will not be computed twice with the same idempotency
+/// key.
+fn classify_reqwest_error(e: reqwest::Error) -> RequestError {
+    if e.is_builder() || e.is_redirect() {
+        RequestError::Fatal(e.into())
+    } else {
+        RequestError::Transient(e.into())
+    }
+}
+
 impl Client {
-    pub(super) async fn post(&self, end_point: &str, data: impl serde::Serialize) -> Result<String> {
-        let uri = format!("{}/{end_point}", self.service_uri);
-        let resp = self.client.post(&uri).json(&data).send().await?.text().await?;
+    /// Post `body` in `content_type` to `end_point`, asking for response in
+    /// the same content type. Transient failures will be retried on all
+    /// servers with exponential backoff.
+    pub(super) async fn post_bytes(&self, end_point: &str, content_type: &str, body: impl Into<Bytes>) -> Result<Bytes> {
+        ensure!(!self.endpoints.is_empty(), "no server to connect");
+        let body = body.into();
+        // the same key for all retries
+        let key = lock::generate_token()?;
+        let mut delay = self.retry.initial_delay;
+        let mut last_error = None;
+        for attempt in 0..=self.retry.max_retries {
+            if attempt > 0 {
+                debug!("retry #{attempt} in {delay:.1} seconds ...");
+                tokio::time::sleep(std::time::Duration::from_secs_f64(delay)).await;
+                delay = (delay * 2.0).min(self.retry.max_delay);
+            }
+            // the server could be restarted on another address
+            for ep in self.endpoints.iter().map(Endpoint::resolve) {
+                match self.post_once(&ep, end_point, content_type, &key, body.clone()).await {
+                    Ok(resp) => return Ok(resp),
+                    Err(RequestError::Fatal(err)) => return Err(err),
+                    Err(RequestError::Transient(err)) => {
+                        warn!("request to {} failed: {err:?}", ep.describe());
+                        last_error = Some(err);
+                    }
+                }
+            }
+        }
+        let err = last_error.expect("last error");
+        Err(err.context(format!("request failed after {} retries", self.retry.max_retries)))
+    }
 
-        Ok(resp)
+    async fn post_once(
+        &self,
+        ep: &Endpoint,
+        end_point: &str,
+        content_type: &str,
+        key: &str,
+        body: Bytes,
+    ) -> Result<Bytes, RequestError> {
+        use reqwest::header::{ACCEPT, CONTENT_TYPE};
+
+        if let Some(path) = &ep.unix {
+            return self.post_unix(ep, path, end_point, content_type, key, body).await;
+        }
+
+        let uri = format!("{}/{end_point}", ep.service_uri);
+        let mut req = self
+            .client
+            .post(&uri)
+            .header(CONTENT_TYPE, content_type)
+            .header(ACCEPT, content_type)
+            .header(IDEMPOTENCY_KEY, key)
+            .body(body);
+        if let Some(token) = &ep.token {
+            req = req.bearer_auth(token);
+        }
+        let resp = req.send().await.map_err(classify_reqwest_error)?;
+        let status = resp.status();
+        let body = resp.bytes().await.map_err(classify_reqwest_error)?;
+        check_status(&uri, status.as_u16(), body)
     }
+
+    /// Post `body` to service on unix domain socket in `path`, which is not
+    /// supported by reqwest.
+    async fn post_unix(
+        &self,
+        ep: &Endpoint,
+        path: &Path,
+        end_point: &str,
+        content_type: &str,
+        key: &str,
+        body: Bytes,
+    ) -> Result<Bytes, RequestError> {
+        use hyper::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, HOST};
+
+        let stream = tokio::net::UnixStream::connect(path)
+            .await
+            .with_context(|| format!("connect to {path:?}"))
+            .map_err(RequestError::Transient)?;
+        let (mut sender, conn) = hyper::client::conn::handshake(stream)
+            .await
+            .map_err(|e| RequestError::Transient(e.into()))?;
+        tokio::spawn(async move {
+            if let Err(err) = conn.await {
+                error!("connection error: {err:?}");
+            }
+        });
+
+        let mut req = hyper::Request::post(format!("/{end_point}"))
+            .header(HOST, "localhost")
+            .header(CONTENT_TYPE, content_type)
+            .header(ACCEPT, content_type)
+            .header(IDEMPOTENCY_KEY, key);
+        if let Some(token) = &ep.token {
+            req = req.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let req = req
+            .body(hyper::Body::from(body))
+            .map_err(|e| RequestError::Fatal(e.into()))?;
+        let resp = sender
+            .send_request(req)
+            .await
+            .map_err(|e| RequestError::Transient(e.into()))?;
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body())
+            .await
+            .map_err(|e| RequestError::Transient(e.into()))?;
+        check_status(&format!("{path:?}"), status.as_u16(), body)
+    }
+}
+
+/// Check response `status` from server `uri`.
+fn check_status(uri: &str, status: u16, body: Bytes) -> Result<Bytes, RequestError> {
+    let msg = || format!("{uri}: status {status}: {}", String::from_utf8_lossy(&body));
+    match status {
+        200..=299 => Ok(body),
+        401 => Err(RequestError::Fatal(format_err!(
+            "unauthorized request to {uri}: invalid or missing token"
+        ))),
+        s if is_transient_status(s) => Err(RequestError::Transient(format_err!("{}", msg()))),
+        _ => Err(RequestError::Fatal(format_err!("{}", msg()))),
+    }
+}
+
+#[test]
+fn test_check_status() {
+    let check = |status| check_status("test", status, Bytes::from_static(b"body"));
+    assert!(matches!(check(200), Ok(_)));
+    for status in [408, 429, 502, 503, 504] {
+        assert!(matches!(check(status), Err(RequestError::Transient(_))));
+    }
+    for status in [400, 401, 404, 500] {
+        assert!(matches!(check(status), Err(RequestError::Fatal(_))));
+    }
+}
+
+#[tokio::test]
+async fn test_retry_failover() -> Result<()> {
+    use axum::extract::Extension;
+    use axum::http::{HeaderMap, StatusCode};
+    use std::sync::{Arc, Mutex};
+
+    // a server unavailable for the first request
+    type Seen = Arc<Mutex<Vec<String>>>;
+    async fn flaky(Extension(seen): Extension<Seen>, headers: HeaderMap) -> (StatusCode, &'static str) {
+        let key = headers[IDEMPOTENCY_KEY].to_str().unwrap().to_owned();
+        let mut seen = seen.lock().unwrap();
+        seen.push(key);
+        if seen.len() == 1 {
+            (StatusCode::SERVICE_UNAVAILABLE, "busy")
+        } else {
+            (StatusCode::OK, "done")
+        }
+    }
+    let seen = Seen::default();
+    let app = axum::Router::new()
+        .route("/mol", axum::routing::post(flaky))
+        .layer(axum::AddExtensionLayer::new(seen.clone()));
+    let addr = socket::get_free_tcp_address().unwrap();
+    tokio::spawn(axum::Server::bind(&addr).serve(app.into_make_service()));
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    // the first server is down, and the second one is busy at first
+    let retry = RetryOptions {
+        max_retries: 2,
+        initial_delay: 0.01,
+        max_delay: 0.01,
+    };
+    let client = Client::connect_any(["localhost:1".to_owned(), addr.to_string()]).with_retry(retry.clone());
+    let body = client.post_bytes("mol", "application/json", "{}").await?;
+    assert_eq!(&body[..], b"done");
+    // retried with the same idempotency key
+    let seen = seen.lock().unwrap().clone();
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen[0], seen[1]);
+
+    // give up after max retries
+    let client = Client::connect("localhost:1").with_retry(retry);
+    assert!(client.post_bytes("mol", "application/json", "{}").await.is_err());
+
+    Ok(())
 }
 // 743b32f9 ends here
@@ -2,30 +2,359 @@
 use super::*;
 
 use axum::Json;
+use serde::Serialize;
 use std::net::SocketAddr;
 // 3d2c01c2 ends here
 
 // [[file:../../ipi.note::ad35d99c][ad35d99c]]
-// type State = std::sync::Arc<TaskSender>;
-type State = TaskSender;
+use bias::BiasPotential;
+use composite::CompositeModel;
+use constraint::Constraints;
+use recorder::Recorder;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// The backend for computing molecules
+#[derive(Clone)]
+pub(super) enum Backend {
+    /// A single driver connected to our i-PI server
+    Driver(TaskSender),
+    /// Several drivers combined in one model
+    Composite(Arc<CompositeModel>),
+}
+
+impl From<TaskSender> for Backend {
+    fn from(task: TaskSender) -> Self {
+        Self::Driver(task)
+    }
+}
+
+impl From<CompositeModel> for Backend {
+    fn from(model: CompositeModel) -> Self {
+        Self::Composite(Arc::new(model))
+    }
+}
+
+/// Results of recent requests indexed by idempotency key, so that retried
+/// requests will not be computed twice. The digest of molecule is kept
+/// with each key, so that a reused key will not return results of another
+/// molecule.
+#[derive(Default)]
+struct ResultCache {
+    cells: HashMap<String, (u64, Arc<tokio::sync::OnceCell<Computed>>)>,
+    /// Keys in order of insertion, for evicting old results
+    keys: VecDeque<String>,
+}
+
+const MAX_CACHED_RESULTS: usize = 64;
+
+/// Return the digest of species, positions and lattice of `mol`.
+fn molecule_digest(mol: &Molecule) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    for (s, p) in mol.symbols().zip(mol.positions()) {
+        s.hash(&mut hasher);
+        p.map(f64::to_bits).hash(&mut hasher);
+    }
+    if let Some(lat) = mol.get_lattice() {
+        for v in lat.vectors() {
+            [v[0], v[1], v[2]].map(f64::to_bits).hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+impl ResultCache {
+    /// Return the result cell for request with `key` for molecule in
+    /// `digest`. Reusing a key for a different molecule is an error.
+    fn entry(&mut self, key: &str, digest: u64) -> Result<Arc<tokio::sync::OnceCell<Computed>>> {
+        if let Some((d, cell)) = self.cells.get(key) {
+            ensure!(*d == digest, "idempotency key {key} was used for a different molecule");
+            return Ok(cell.clone());
+        }
+        if self.keys.len() >= MAX_CACHED_RESULTS {
+            if let Some(old) = self.keys.pop_front() {
+                self.cells.remove(&old);
+            }
+        }
+        let cell = Arc::new(tokio::sync::OnceCell::new());
+        self.keys.push_back(key.to_owned());
+        self.cells.insert(key.to_owned(), (digest, cell.clone()));
+        Ok(cell)
+    }
+}
+
+#[test]
+fn test_result_cache() -> Result<()> {
+    let mol = Molecule::from_file("tests/files/quinone.cif")?;
+    let mut other = mol.clone();
+    let positions: Vec<_> = mol.positions().map(|[x, y, z]| [x, y, z + 1e-3]).collect();
+    other.set_positions(positions);
+    let (d1, d2) = (molecule_digest(&mol), molecule_digest(&other));
+    assert_ne!(d1, d2);
+
+    let mut cache = ResultCache::default();
+    let cell = cache.entry("a", d1)?;
+    // retried request shares the same result
+    assert!(Arc::ptr_eq(&cell, &cache.entry("a", d1)?));
+    // reused key for another molecule is rejected
+    assert!(cache.entry("a", d2).is_err());
+    assert!(!Arc::ptr_eq(&cell, &cache.entry("b", d1)?));
+
+    // old results are evicted
+    for i in 0..MAX_CACHED_RESULTS {
+        cache.entry(&i.to_string(), d1)?;
+    }
+    assert!(cache.entry("a", d2).is_ok());
+    assert_eq!(cache.keys.len(), MAX_CACHED_RESULTS);
+
+    Ok(())
+}
+
+/// Shared state between route handlers
+#[derive(Clone)]
+pub(super) struct State {
+    backend: Backend,
+    bias: Option<Arc<BiasPotential>>,
+    constraints: Option<Arc<Constraints>>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    /// The token required for accessing REST service
+    token: Option<Arc<str>>,
+    cache: Arc<Mutex<ResultCache>>,
+}
+
+impl State {
+    pub(super) fn new(backend: Backend, options: &ServerOptions, token: Option<String>) -> Self {
+        Self {
+            backend,
+            bias: options.bias.clone().map(Arc::new),
+            constraints: options.constraints.clone().map(Arc::new),
+            recorder: options.recorder.clone().map(|o| Arc::new(Mutex::new(Recorder::new(o)))),
+            token: token.map(Into::into),
+            cache: Arc::default(),
+        }
+    }
+
+    /// Compute `mol` only once for requests with the same idempotency
+    /// `key`, which could be retried by client on transient failures. The
+    /// results are returned with `mol` as submitted in this request.
+    async fn compute_once(&self, key: Option<&str>, mol: Molecule) -> Result<(Molecule, Computed)> {
+        match key {
+            Some(key) => {
+                let cell = self.cache.lock().unwrap().entry(key, molecule_digest(&mol))?;
+                let computed = cell.get_or_try_init(|| self.compute_results(&mol)).await?;
+                Ok((mol, computed.clone()))
+            }
+            None => self.compute(mol).await,
+        }
+    }
+
+    /// Compute `mol` using external code, and return it as submitted
+    /// together with the computed results, so they are kept with all
+    /// metadata of the input.
+    pub(super) async fn compute(&self, mol: Molecule) -> Result<(Molecule, Computed)> {
+        let computed = self.compute_results(&mol).await?;
+        Ok((mol, computed))
+    }
+
+    /// Compute `mol` using external code, and add bias potential if any.
+    /// The raw results from external code will be recorded if recorder
+    /// enabled, before bias and constraints are imposed on forces.
+    async fn compute_results(&self, mol: &Molecule) -> Result<Computed> {
+        let mut computed = match &self.backend {
+            Backend::Driver(task) => task.remote_compute(mol.clone()).await?,
+            Backend::Composite(model) => model.compute(mol).await?,
+        };
+        ensure!(
+            computed.forces.len() == mol.natoms(),
+            "computed forces for {} atoms, but {} atoms submitted",
+            computed.forces.len(),
+            mol.natoms()
+        );
+        // NOTE: biased or constrained forces are not physical, so they are not
+        // recorded as training data
+        if let Some(recorder) = &self.recorder {
+            // NOTE: recording does blocking file I/O, which should not stall
+            // the async runtime
+            let recorder = recorder.clone();
+            let (mol, raw) = (mol.clone(), computed.clone());
+            let recorded = tokio::task::spawn_blocking(move || recorder.lock().unwrap().record(&mol, &raw)).await;
+            // NOTE: failed recording should not fail the computation
+            match recorded {
+                Ok(Err(err)) => error!("failed to record structure: {err:?}"),
+                Err(err) => error!("recording task failed: {err:?}"),
+                Ok(Ok(())) => {}
+            }
+        }
+        if let Some(bias) = &self.bias {
+            bias.apply(mol, &mut computed)?;
+        }
+        if let Some(constraints) = &self.constraints {
+            constraints.apply(mol, &mut computed)?;
+        }
+        Ok(computed)
+    }
+}
+
+#[tokio::test]
+async fn test_record_raw_results() -> Result<()> {
+    // a driver returning constant energy and zero forces
+    let (mut task_rx, task_tx) = task::Task::new().split();
+    tokio::spawn(async move {
+        while let Some((mol, tx_out)) = task_rx.recv().await {
+            let computed = Computed {
+                energy: -1.0,
+                forces: vec![[0.0; 3]; mol.natoms()],
+                virial: [0.0; 9],
+                extra: String::new(),
+            };
+            let _ = tx_out.send(computed);
+        }
+    });
+
+    let path = std::env::temp_dir().join(format!("gosh-ipi-record-{}.xyz", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let bias = r#"{"restraints": [{"type": "position", "atom": 1, "k": 10.0, "position": [-1.0, -1.0, -1.0]}]}"#;
+    let options = ServerOptions {
+        bias: Some(serde_json::from_str(bias)?),
+        recorder: Some(recorder::RecorderOptions {
+            path: path.clone(),
+            max_size: None,
+            dedup: None,
+        }),
+        ..Default::default()
+    };
+    let state = State::new(task_tx.into(), &options, None);
+    let mut mol = Molecule::from_file("tests/files/quinone.cif")?;
+    mol.set_title("submitted");
+    let (mol, computed) = state.compute(mol).await?;
+    // the submitted molecule is returned as it is
+    assert_eq!(mol.title(), "submitted");
+    // the caller gets biased results
+    assert!(computed.energy > -1.0);
+    assert!(computed.extra.contains("bias_energy"));
+
+    // but the raw results from driver are recorded
+    let recorded = std::fs::read_to_string(&path)?;
+    std::fs::remove_file(&path)?;
+    let comment = recorded.lines().nth(1).unwrap();
+    assert!(comment.contains("energy=-1 "));
+    assert!(!comment.contains("bias_energy"));
+    assert!(recorded.lines().skip(2).all(|line| line.ends_with("0.00000000")));
+
+    Ok(())
+}
 // ad35d99c ends here
 
 // [[file:../../ipi.note::7157f9ad][7157f9ad]]
+use axum::body::Bytes;
 use axum::extract::Extension;
-use axum::http::StatusCode;
-use axum::response::IntoResponse;
-
-async fn compute_mol(Json(mol): Json<Molecule>, client: Extension<State>) -> impl IntoResponse {
-    match client.remote_compute(mol).await {
-        Ok(computed) => {
-            let mut mp = ModelProperties::default();
-            mp.set_energy(computed.energy);
-            mp.set_forces(computed.forces);
-            (StatusCode::OK, Json(mp))
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{Headers, IntoResponse, Response};
+
+/// Computed model properties for the submitted molecule, with virial and
+/// extra data from client code, which can be ignored when deserializing as
+/// `ModelProperties`.
+#[derive(Debug, Serialize)]
+pub(super) struct ComputedResponse {
+    #[serde(flatten)]
+    mp: ModelProperties,
+    virial: [f64; 9],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extra: Option<serde_json::Value>,
+}
+
+impl ComputedResponse {
+    /// Construct response from `computed` results for submitted `mol`.
+    pub(super) fn new(mol: Molecule, computed: Computed) -> Self {
+        let extra = match computed.extra.trim() {
+            "" => None,
+            s => serde_json::from_str(s).ok().or_else(|| Some(s.into())),
+        };
+        let mut mp = ModelProperties::default();
+        mp.set_energy(computed.energy);
+        mp.set_forces(computed.forces);
+        mp.set_molecule(mol);
+        Self {
+            mp,
+            virial: computed.virial,
+            extra,
+        }
+    }
+}
+
+/// Compare tokens in constant time to avoid timing attack.
+fn token_matches(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len()
+        && expected
+            .bytes()
+            .zip(provided.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[test]
+fn test_token_matches() {
+    assert!(token_matches("abc", "abc"));
+    assert!(!token_matches("abc", "abd"));
+    assert!(!token_matches("abc", "ab"));
+}
+
+/// Extractor checking the bearer token in `Authorization` header against
+/// the token in shared state. Required by every route.
+pub(super) struct Authorized;
+
+#[axum::async_trait]
+impl<B: Send> axum::extract::FromRequest<B> for Authorized {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request(req: &mut axum::extract::RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(state) = Extension::<State>::from_request(req)
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "missing server state"))?;
+        let expected = match &state.token {
+            Some(token) => token,
+            None => return Ok(Self),
+        };
+        let provided = req
+            .headers()
+            .and_then(|h| h.get(axum::http::header::AUTHORIZATION))
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match provided {
+            Some(token) if token_matches(expected, token) => Ok(Self),
+            _ => Err((StatusCode::UNAUTHORIZED, "invalid or missing token")),
         }
+    }
+}
+
+/// Compute molecule posted in JSON or compact binary format, replying in
+/// the format negotiated by `Accept` header (JSON by default).
+async fn compute_mol(_: Authorized, state: Extension<State>, headers: HeaderMap, body: Bytes) -> Response {
+    use axum::http::header::{ACCEPT, CONTENT_TYPE};
+
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    let mol = if binary::is_binary(header(CONTENT_TYPE)) {
+        binary::decode_molecule(body)
+    } else {
+        serde_json::from_slice(&body).context("invalid json molecule")
+    };
+    let mol = match mol {
+        Ok(mol) => mol,
+        Err(err) => return (StatusCode::BAD_REQUEST, format!("{err:?}")).into_response(),
+    };
+
+    let key = headers.get(client::IDEMPOTENCY_KEY).and_then(|v| v.to_str().ok());
+    match state.compute_once(key, mol).await {
+        Ok((_, computed)) if binary::is_binary(header(ACCEPT)) => {
+            let content_type = Headers([(CONTENT_TYPE, binary::BINARY_CONTENT_TYPE)]);
+            (content_type, binary::encode_computed(&computed)).into_response()
+        }
+        Ok((mol, computed)) => Json(ComputedResponse::new(mol, computed)).into_response(),
         Err(err) => {
-            dbg!(err);
-            todo!();
+            error!("failed to compute molecule: {err:?}");
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:?}")).into_response()
         }
     }
 }
@@ -39,13 +368,14 @@ macro_rules! build_app_with_routes {
 
         axum::Router::new()
             .route("/mol", post(compute_mol))
+            .route("/stream", axum::routing::get(stream::ws_stream))
             .layer(AddExtensionLayer::new($state))
     }};
 }
 // 59c3364a ends here
 
 // [[file:../../ipi.note::415dc72b][415dc72b]]
-async fn shutdown_signal() {
+pub(super) async fn shutdown_signal() {
     use tokio::signal;
 
     let ctrl_c = async {
@@ -69,22 +399,70 @@ async fn shutdown_signal() {
 // 415dc72b ends here
 
 // [[file:../../ipi.note::f4a1566d][f4a1566d]]
+use socket::UnixSocketFile;
+use std::future::Future;
+use tokio::net::UnixListener;
+
+/// Where the restful service listens on
+pub(super) enum RestListener {
+    /// TCP socket address to bind
+    Tcp(SocketAddr),
+    /// Unix domain socket, with its file removed when dropped
+    Unix(UnixListener, UnixSocketFile, PathBuf),
+}
+
+impl RestListener {
+    /// Return the address for clients to connect: "host:port" for TCP, and
+    /// "unix:/path/to/socket" for unix domain socket.
+    pub(super) fn address(&self) -> String {
+        match self {
+            Self::Tcp(addr) => addr.to_string(),
+            Self::Unix(_, _, path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+/// Accept incoming connections on unix domain socket for hyper
+struct UnixAccept(UnixListener);
+
+impl hyper::server::accept::Accept for UnixAccept {
+    type Conn = tokio::net::UnixStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<std::io::Result<Self::Conn>>> {
+        self.0.poll_accept(cx).map(|r| Some(r.map(|(stream, _)| stream)))
+    }
+}
+
 impl Server {
     /// Start restful service
     ///
     /// # Parameters
     ///
-    /// * addr: socket address to bind
+    /// * listener: TCP address or unix domain socket to listen on
     /// * state: shared state between route handlers
-    pub(super) async fn run_restful(addr: impl Into<SocketAddr>, state: State) {
+    /// * shutdown: stop service gracefully when completed
+    pub(super) async fn run_restful(listener: RestListener, state: State, shutdown: impl Future<Output = ()>) {
         let app = build_app_with_routes!(state);
-        let addr = addr.into();
 
-        if let Err(err) = axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            .with_graceful_shutdown(shutdown_signal())
-            .await
-        {
+        let ret = match listener {
+            RestListener::Tcp(addr) => {
+                axum::Server::bind(&addr)
+                    .serve(app.into_make_service())
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            RestListener::Unix(listener, _file, _) => {
+                axum::Server::builder(UnixAccept(listener))
+                    .serve(app.into_make_service())
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+        };
+        if let Err(err) = ret {
             error!("error in restful serivce: {err:?}");
         }
     }
@@ -0,0 +1,303 @@
+// [[file:../../ipi.note::6c0e3b59][6c0e3b59]]
+use super::*;
+use server::{Authorized, State};
+
+use serde::{Deserialize, Serialize};
+// 6c0e3b59 ends here
+
+// [[file:../../ipi.note::b4e27a1d][b4e27a1d]]
+/// One step sent by client in streaming session. The species and lattice
+/// are only required in the first frame, and will be reused in following
+/// frames unless changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct StreamFrame {
+    /// Cartesian coordinates of all atoms in Å
+    positions: Vec<[f64; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symbols: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lattice: Option<[[f64; 3]; 3]>,
+}
+
+/// Computed results replied for each step in streaming session, without
+/// the molecule which is known by client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StreamReply {
+    energy: f64,
+    forces: Vec<[f64; 3]>,
+    virial: [f64; 9],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extra: Option<serde_json::Value>,
+}
+
+impl From<Computed> for StreamReply {
+    fn from(computed: Computed) -> Self {
+        let extra = match computed.extra.trim() {
+            "" => None,
+            s => serde_json::from_str(s).ok().or_else(|| Some(s.into())),
+        };
+        Self {
+            energy: computed.energy,
+            forces: computed.forces,
+            virial: computed.virial,
+            extra,
+        }
+    }
+}
+
+impl From<StreamReply> for Computed {
+    fn from(reply: StreamReply) -> Self {
+        let extra = match reply.extra {
+            None => String::new(),
+            Some(serde_json::Value::String(s)) => s,
+            Some(v) => v.to_string(),
+        };
+        Self {
+            energy: reply.energy,
+            forces: reply.forces,
+            virial: reply.virial,
+            extra,
+        }
+    }
+}
+
+/// Update molecule in streaming session with `frame`, returning the
+/// molecule to compute.
+fn apply_frame(mol: &mut Option<Molecule>, frame: StreamFrame) -> Result<Molecule> {
+    if let Some(symbols) = frame.symbols {
+        ensure!(symbols.len() == frame.positions.len(), "inconsistent number of atoms in frame");
+        let atoms = symbols.iter().zip(&frame.positions).map(|(s, &p)| Atom::new(s.as_str(), p));
+        *mol = Some(Molecule::from_atoms(atoms));
+    }
+    let mol = mol.as_mut().ok_or(format_err!("species not set in the first frame"))?;
+    ensure!(
+        mol.natoms() == frame.positions.len(),
+        "expect positions of {} atoms, but got {}",
+        mol.natoms(),
+        frame.positions.len()
+    );
+    mol.set_positions(frame.positions);
+    if let Some(lattice) = frame.lattice {
+        mol.set_lattice(Lattice::new(lattice));
+    }
+    Ok(mol.clone())
+}
+
+#[test]
+fn test_apply_frame() {
+    let mut mol = None;
+    let frame = StreamFrame {
+        positions: vec![[0.0; 3]],
+        ..Default::default()
+    };
+    assert!(apply_frame(&mut mol, frame.clone()).is_err());
+
+    let first = StreamFrame {
+        positions: vec![[0.0; 3], [0.0, 0.0, 0.96]],
+        symbols: Some(vec!["H".into(), "F".into()]),
+        lattice: Some([[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [0.0, 0.0, 10.0]]),
+    };
+    let m = apply_frame(&mut mol, first).unwrap();
+    assert_eq!(m.natoms(), 2);
+    assert!(m.is_periodic());
+
+    // species and lattice reused
+    let next = StreamFrame {
+        positions: vec![[0.0; 3], [0.0, 0.0, 1.0]],
+        ..Default::default()
+    };
+    let m = apply_frame(&mut mol, next).unwrap();
+    assert!(m.is_periodic());
+    assert_eq!(m.symbols().last(), Some("F"));
+    assert_eq!(m.positions().last(), Some([0.0, 0.0, 1.0]));
+    // wrong number of atoms
+    assert!(apply_frame(&mut mol, frame).is_err());
+}
+
+#[test]
+fn test_stream_reply() {
+    let computed = Computed {
+        energy: -1.0,
+        forces: vec![[0.1, 0.2, 0.3]],
+        virial: [0.5; 9],
+        extra: r#"{"bias_energy": 0.1}"#.into(),
+    };
+    let reply = serde_json::to_string(&StreamReply::from(computed)).unwrap();
+    // no molecule in reply
+    let value: serde_json::Value = serde_json::from_str(&reply).unwrap();
+    assert_eq!(value.as_object().unwrap().len(), 4);
+    let c: Computed = serde_json::from_str::<StreamReply>(&reply).unwrap().into();
+    assert_eq!(c.energy, -1.0);
+    assert_eq!(c.virial, [0.5; 9]);
+    assert!(c.extra.contains("bias_energy"));
+}
+// b4e27a1d ends here
+
+// [[file:../../ipi.note::e05a9f62][e05a9f62]]
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Extension;
+use axum::response::IntoResponse;
+
+/// Persistent streaming endpoint: client sends positions for each step,
+/// and receives energy and forces in the same order.
+pub(super) async fn ws_stream(_: Authorized, ws: WebSocketUpgrade, Extension(state): Extension<State>) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| serve_stream(socket, state))
+}
+
+async fn serve_stream(mut socket: WebSocket, state: State) {
+    let mut mol = None;
+    while let Some(Ok(msg)) = socket.recv().await {
+        let text = match msg {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let reply = match compute_frame(&state, &mut mol, &text).await {
+            Ok(computed) => serde_json::to_string(&StreamReply::from(computed)),
+            Err(err) => {
+                error!("stream: {err:?}");
+                serde_json::to_string(&serde_json::json!({ "error": format!("{err:?}") }))
+            }
+        };
+        let reply = reply.expect("json reply");
+        if socket.send(Message::Text(reply)).await.is_err() {
+            break;
+        }
+    }
+    debug!("stream session closed");
+}
+
+async fn compute_frame(state: &State, mol: &mut Option<Molecule>, text: &str) -> Result<Computed> {
+    let frame: StreamFrame = serde_json::from_str(text).context("invalid stream frame")?;
+    let mol = apply_frame(mol, frame)?;
+    let (_, computed) = state.compute(mol).await?;
+    Ok(computed)
+}
+// e05a9f62 ends here
+
+// [[file:../../ipi.note::5a7d19c3][5a7d19c3]]
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::WebSocketStream;
+
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// A persistent session streaming molecules to proxy server for
+/// computation. Only positions are sent for each step, unless species or
+/// lattice changed.
+pub struct StreamSession {
+    ws: WebSocketStream<Box<dyn AsyncStream>>,
+    symbols: Vec<String>,
+    lattice: Option<[[f64; 3]; 3]>,
+}
+
+impl Client {
+    /// Open a streaming session with the first available server.
+    pub async fn stream(&self) -> Result<StreamSession> {
+        let mut last_error = None;
+        for ep in self.endpoints.iter().map(client::Endpoint::resolve) {
+            match open_stream(&ep).await {
+                Ok(session) => return Ok(session),
+                Err(err) => {
+                    warn!("failed to open stream: {err:?}");
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| format_err!("no server to connect")))
+    }
+}
+
+async fn open_stream(ep: &client::Endpoint) -> Result<StreamSession> {
+    use axum::http::header::AUTHORIZATION;
+
+    let url = format!("ws{}/stream", ep.service_uri.trim_start_matches("http"));
+    let mut req = url.into_client_request()?;
+    if let Some(token) = &ep.token {
+        req.headers_mut().insert(AUTHORIZATION, format!("Bearer {token}").parse()?);
+    }
+    let stream: Box<dyn AsyncStream> = match &ep.unix {
+        Some(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        None => {
+            let addr = ep.service_uri.trim_start_matches("http://");
+            Box::new(tokio::net::TcpStream::connect(addr).await?)
+        }
+    };
+    let (ws, _) = tokio_tungstenite::client_async(req, stream)
+        .await
+        .context("websocket handshake")?;
+
+    Ok(StreamSession {
+        ws,
+        symbols: vec![],
+        lattice: None,
+    })
+}
+
+impl StreamSession {
+    /// Request server to compute `mol` in this session, returning all
+    /// computed results including virial and extra data from external code.
+    pub async fn compute_results(&mut self, mol: &Molecule) -> Result<Computed> {
+        let mut frame = StreamFrame {
+            positions: mol.positions().collect(),
+            ..Default::default()
+        };
+        let symbols: Vec<_> = mol.symbols().map(|s| s.to_owned()).collect();
+        let lattice = mol.get_lattice().map(|lat| {
+            let vs = lat.vectors();
+            std::array::from_fn(|i| [vs[i][0], vs[i][1], vs[i][2]])
+        });
+        // NOTE: removing lattice requires resetting the whole molecule
+        if symbols != self.symbols || (lattice.is_none() && self.lattice.is_some()) {
+            self.symbols = symbols.clone();
+            frame.symbols = Some(symbols);
+        }
+        // the molecule will be rebuilt on server when species sent
+        if lattice != self.lattice || frame.symbols.is_some() {
+            self.lattice = lattice;
+            frame.lattice = lattice;
+        }
+
+        self.ws.send(WsMessage::Text(serde_json::to_string(&frame)?)).await?;
+        loop {
+            let msg = self.ws.next().await.ok_or(format_err!("stream closed by server"))??;
+            match msg {
+                WsMessage::Text(text) => {
+                    let value: serde_json::Value = serde_json::from_str(&text)?;
+                    if let Some(err) = value.get("error") {
+                        bail!("server failed to compute: {err}");
+                    }
+                    let reply: StreamReply = serde_json::from_value(value)?;
+                    ensure!(
+                        reply.forces.len() == mol.natoms(),
+                        "server returned forces for a different number of atoms"
+                    );
+                    return Ok(reply.into());
+                }
+                WsMessage::Close(_) => bail!("stream closed by server"),
+                _ => continue,
+            }
+        }
+    }
+
+    /// Request server to compute `mol` in this session. The returned
+    /// properties carry `mol` as submitted.
+    pub async fn compute(&mut self, mol: &Molecule) -> Result<ModelProperties> {
+        let computed = self.compute_results(mol).await?;
+        let mut mp = ModelProperties::default();
+        mp.set_energy(computed.energy);
+        mp.set_forces(computed.forces);
+        mp.set_molecule(mol.clone());
+        Ok(mp)
+    }
+
+    /// Close the session.
+    pub async fn close(mut self) -> Result<()> {
+        self.ws.close(None).await?;
+        Ok(())
+    }
+}
+// 5a7d19c3 ends here
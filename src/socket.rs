@@ -1,13 +1,28 @@
 // [[file:../ipi.note::14beb047][14beb047]]
 use super::*;
 
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, UnixListener};
 use tokio::net::{TcpStream, UnixStream};
-use tokio_util::codec::{FramedRead, FramedWrite};
 // 14beb047 ends here
 
 // [[file:../ipi.note::624a82ac][624a82ac]]
 /// A socket for i-PI client or driver
+///
+/// # ASE compatibility
+///
+/// The conventions of ASE's `SocketIOCalculator` (server) and `SocketClient`
+/// (driver) are followed, so that gosh-ipi interoperates with ASE in both
+/// roles:
+///
+/// * the unix domain socket for plain name `host` is `/tmp/ipi_{host}`
+/// * the server polls STATUS, and sends INIT on NEEDINIT before each POSDATA
+/// * the driver asks for INIT again after sending forces
+/// * the init string is sent as one zero byte, and zero-byte padding in init
+///   string or extra data is ignored
+///
+/// Note that ASE uses 31415 as the default port for internet socket.
 #[derive(Debug, Clone)]
 pub struct Socket {}
 
@@ -17,35 +32,296 @@ fn guess_unix_socket_file(host: &str) -> String {
 }
 // 624a82ac ends here
 
+// [[file:../ipi.note::0b5c9e13][0b5c9e13]]
+/// The address of unix domain socket
+#[derive(Debug, Clone, PartialEq)]
+enum UnixSocketAddr {
+    /// A socket file in file system
+    Path(PathBuf),
+    /// A name in Linux abstract namespace
+    Abstract(String),
+}
+
+impl UnixSocketAddr {
+    /// Resolve unix socket address from `host`:
+    ///
+    /// * `@name`: `name` in Linux abstract namespace
+    /// * a path containing `/`: the socket file at this path
+    /// * a plain name: the socket file `/tmp/ipi_{name}` as in i-PI and ASE
+    fn from_host(host: &str) -> Self {
+        if let Some(name) = host.strip_prefix('@') {
+            Self::Abstract(name.into())
+        } else if host.contains('/') {
+            Self::Path(host.into())
+        } else {
+            Self::Path(guess_unix_socket_file(host).into())
+        }
+    }
+}
+
+#[test]
+fn test_unix_socket_addr() {
+    assert_eq!(UnixSocketAddr::from_host("@ipi"), UnixSocketAddr::Abstract("ipi".into()));
+    assert_eq!(UnixSocketAddr::from_host("./ipi.sock"), UnixSocketAddr::Path("./ipi.sock".into()));
+    assert_eq!(UnixSocketAddr::from_host("ipi"), UnixSocketAddr::Path("/tmp/ipi_ipi".into()));
+}
+
+/// Describe the i-PI address for `host`, `port` and `unix` in a human
+/// readable way: `host:port` for internet socket, the path of socket file or
+/// `@name` for unix domain socket.
+pub(crate) fn describe_address(host: &str, port: u16, unix: bool) -> String {
+    if unix {
+        match UnixSocketAddr::from_host(host) {
+            UnixSocketAddr::Path(path) => path.display().to_string(),
+            UnixSocketAddr::Abstract(name) => format!("@{name}"),
+        }
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// Bind unix domain socket file in `path` only accessible by its owner, for
+/// local services other than i-PI.
+///
+/// The socket is bound in a private directory first, and then moved into
+/// `path` after its permissions restricted, so that it is never accessible
+/// by other users, whatever the umask is.
+pub(crate) fn bind_private_unix_socket(path: &Path) -> Result<(UnixListener, UnixSocketFile)> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    remove_stale_socket_file(path)?;
+    let name = path.file_name().ok_or(format_err!("invalid socket path: {path:?}"))?;
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let dir = parent.join(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("create private directory {dir:?}"))?;
+    let tmp = dir.join("socket");
+    let bound = (|| {
+        let listener = UnixListener::bind(&tmp).context("binding on uds")?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("move socket into {path:?}"))?;
+        Result::<_>::Ok(listener)
+    })();
+    let _ = std::fs::remove_file(&tmp);
+    let _ = std::fs::remove_dir(&dir);
+
+    Ok((bound?, UnixSocketFile(Some(path.to_owned()))))
+}
+
+#[tokio::test]
+async fn test_private_unix_socket() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::temp_dir().join(format!("gosh-ipi-private-{}.sock", std::process::id()));
+    let (listener, file) = bind_private_unix_socket(&path)?;
+    assert_eq!(path.metadata()?.permissions().mode() & 0o777, 0o600);
+    // no temporary directory left
+    let parent = path.parent().unwrap();
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+    let left = std::fs::read_dir(parent)?.filter_map(|e| e.ok()).any(|e| {
+        let n = e.file_name().to_string_lossy().to_string();
+        n.starts_with(&format!(".{name}"))
+    });
+    assert!(!left);
+    // the moved socket is still connectable
+    let (accepted, connected) = tokio::join!(listener.accept(), UnixStream::connect(&path));
+    accepted?;
+    connected?;
+    drop(file);
+    assert!(!path.exists());
+
+    Ok(())
+}
+
+/// Remove stale socket file in `path` left by a dead process. Refuse to
+/// remove it if it is not a socket, or another process is listening on it.
+fn remove_stale_socket_file(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        ensure!(metadata.file_type().is_socket(), "{path:?} exists, but is not a socket");
+        match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => bail!("another process is listening on {path:?}"),
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                info!("remove stale socket file: {path:?}");
+                std::fs::remove_file(path)?;
+            }
+            Err(e) => bail!("failed to check socket file {path:?}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// The socket file created by listener, which will be removed when dropped.
+#[derive(Debug)]
+pub struct UnixSocketFile(Option<PathBuf>);
+
+impl Drop for UnixSocketFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.0 {
+            debug!("remove socket file: {path:?}");
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl UnixSocketAddr {
+    fn bind(&self) -> Result<(UnixListener, UnixSocketFile)> {
+        match self {
+            Self::Path(path) => {
+                remove_stale_socket_file(path)?;
+                let listener = UnixListener::bind(path).context("binding on uds")?;
+                Ok((listener, UnixSocketFile(Some(path.to_owned()))))
+            }
+            #[cfg(target_os = "linux")]
+            Self::Abstract(name) => {
+                use std::os::linux::net::SocketAddrExt;
+
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                let listener = std::os::unix::net::UnixListener::bind_addr(&addr).context("binding on abstract uds")?;
+                listener.set_nonblocking(true)?;
+                Ok((UnixListener::from_std(listener)?, UnixSocketFile(None)))
+            }
+            #[cfg(not(target_os = "linux"))]
+            Self::Abstract(_) => bail!("abstract unix socket is only supported on Linux"),
+        }
+    }
+
+    async fn connect(&self) -> Result<UnixStream> {
+        match self {
+            Self::Path(path) => Ok(UnixStream::connect(path).await.context("connect to uds")?),
+            #[cfg(target_os = "linux")]
+            Self::Abstract(name) => {
+                use std::os::linux::net::SocketAddrExt;
+
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                let stream = std::os::unix::net::UnixStream::connect_addr(&addr).context("connect to abstract uds")?;
+                stream.set_nonblocking(true)?;
+                Ok(UnixStream::from_std(stream)?)
+            }
+            #[cfg(not(target_os = "linux"))]
+            Self::Abstract(_) => bail!("abstract unix socket is only supported on Linux"),
+        }
+    }
+}
+// 0b5c9e13 ends here
+
 // [[file:../ipi.note::2d2abd6a][2d2abd6a]]
 /// Return the address available for binding with the OS assigns port.
 pub fn get_free_tcp_address() -> Option<std::net::SocketAddr> {
     std::net::TcpListener::bind(("localhost", 0)).ok()?.local_addr().ok()
 }
+
+fn default_keepalive_time() -> f64 {
+    60.0
+}
+
+fn default_keepalive_interval() -> f64 {
+    10.0
+}
+
+fn default_keepalive_retries() -> u32 {
+    6
+}
+
+/// TCP keepalive settings, so that connection silently dropped by peer on
+/// other node (e.g. crashed node or broken network) will be detected by OS
+/// in a few minutes, instead of waiting forever.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct KeepaliveOptions {
+    /// Idle time in seconds before sending the first keepalive probe
+    #[serde(default = "default_keepalive_time")]
+    pub time: f64,
+    /// Interval in seconds between keepalive probes
+    #[serde(default = "default_keepalive_interval")]
+    pub interval: f64,
+    /// Number of unanswered probes before the connection is dropped. Only
+    /// for Linux and macOS.
+    #[serde(default = "default_keepalive_retries")]
+    pub retries: u32,
+}
+
+impl Default for KeepaliveOptions {
+    fn default() -> Self {
+        Self {
+            time: default_keepalive_time(),
+            interval: default_keepalive_interval(),
+            retries: default_keepalive_retries(),
+        }
+    }
+}
+
+/// Enable TCP keepalive on `stream` as in `options`.
+pub(crate) fn set_keepalive(stream: &TcpStream, options: &KeepaliveOptions) -> Result<()> {
+    let keepalive = socket2::TcpKeepalive::new()
+        .with_time(std::time::Duration::from_secs_f64(options.time))
+        .with_interval(std::time::Duration::from_secs_f64(options.interval));
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    let keepalive = keepalive.with_retries(options.retries);
+    socket2::SockRef::from(stream)
+        .set_tcp_keepalive(&keepalive)
+        .context("set TCP keepalive")?;
+    Ok(())
+}
 // 2d2abd6a ends here
 
 // [[file:../ipi.note::9b4b9ee0][9b4b9ee0]]
-#[derive(Debug)]
-/// A stream between i-PI client and driver (server)
-pub enum IpiStream {
-    Tcp(TcpStream),
+type BoxedRead = Box<dyn AsyncRead + Unpin + Send>;
+type BoxedWrite = Box<dyn AsyncWrite + Unpin + Send>;
 
-    #[cfg(unix)]
-    Unix(UnixStream),
+/// A stream between i-PI server and driver (client), over any transport
+/// with async reader and writer, such as TCP, unix domain socket, pipes, or
+/// in-memory `tokio::io::duplex` for tests.
+pub struct IpiStream(pub(crate) ipi::IpiServerStream<BoxedRead, BoxedWrite>);
+
+impl IpiStream {
+    /// Create i-PI stream from split `read` and `write` halves.
+    pub fn new(read: impl AsyncRead + Unpin + Send + 'static, write: impl AsyncWrite + Unpin + Send + 'static) -> Self {
+        Self(ipi::IpiServerStream::new(Box::new(read), Box::new(write)))
+    }
+
+    /// Create i-PI stream from a bidirectional `stream`.
+    pub fn from_stream(stream: impl AsyncRead + AsyncWrite + Send + 'static) -> Self {
+        let (read, write) = tokio::io::split(stream);
+        Self::new(read, write)
+    }
+
+    /// Return the underlying reader and writer.
+    pub(crate) fn into_inner(self) -> (BoxedRead, BoxedWrite) {
+        self.0.into_inner()
+    }
+
+    /// Exchange messages in binary layout specified in `codec`, for drivers
+    /// not following the little-endian layout of i-PI.
+    pub fn set_codec(&mut self, codec: codec::CodecOptions) {
+        self.0.set_codec(codec);
+    }
+}
+
+impl std::fmt::Debug for IpiStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("IpiStream")
+    }
 }
 
 impl Socket {
     /// Opens i-PI connection to a driver.
+    ///
+    /// For unix domain socket, `host` could be a plain name, an explicit
+    /// path, or `@name` in Linux abstract namespace.
     pub async fn connect(host: &str, port: u16, unix: bool) -> Result<IpiStream> {
         let stream = if unix {
-            let sock_file = guess_unix_socket_file(host);
-            debug!("connect to unix domain socket: {sock_file}");
-            let stream = UnixStream::connect(sock_file).await.context("connect to uds")?;
-            IpiStream::Unix(stream)
+            let addr = UnixSocketAddr::from_host(host);
+            debug!("connect to unix domain socket: {addr:?}");
+            let stream = addr.connect().await?;
+            IpiStream::from_stream(stream)
         } else {
             debug!("connecting to socket {host}:{port}");
             let stream = TcpStream::connect((host, port)).await.context("connect to inet")?;
-            IpiStream::Tcp(stream)
+            set_keepalive(&stream, &KeepaliveOptions::default())?;
+            IpiStream::from_stream(stream)
         };
         Ok(stream)
     }
@@ -55,45 +331,316 @@ impl Socket {
 // [[file:../ipi.note::ad23dfbd][ad23dfbd]]
 #[derive(Debug)]
 /// The listener for servier side
-pub enum IpiListener {
+pub struct IpiListener {
+    kind: ListenerKind,
+    /// The binary layout of messages with accepted drivers
+    codec: codec::CodecOptions,
+    /// TCP keepalive settings for accepted drivers on internet socket
+    keepalive: KeepaliveOptions,
+}
+
+#[derive(Debug)]
+pub(crate) enum ListenerKind {
     Tcp(TcpListener),
 
     #[cfg(unix)]
-    Unix(UnixListener),
+    Unix(UnixListener, UnixSocketFile),
+
+    /// Drivers spawned as child processes, talking over stdin/stdout
+    Pipe(PipeLauncher),
+
+    /// TLS-encrypted internet socket for drivers on other nodes
+    Tls(tls::TlsListener),
+}
+
+impl From<ListenerKind> for IpiListener {
+    fn from(kind: ListenerKind) -> Self {
+        Self {
+            kind,
+            codec: codec::CodecOptions::default(),
+            keepalive: KeepaliveOptions::default(),
+        }
+    }
 }
 
 impl Socket {
     /// Listening on incoming connections using unix socket or internet socket.
+    ///
+    /// For unix domain socket, `host` could be a plain name, an explicit
+    /// path, or `@name` in Linux abstract namespace. Stale socket file left
+    /// by dead process will be removed, and the socket file will be removed
+    /// when the listener is dropped.
     pub async fn bind(host: &str, port: u16, unix: bool) -> Result<IpiListener> {
         let x = if unix {
-            let sock_file = guess_unix_socket_file(host);
-            debug!("listening on unix domain socket: {sock_file}");
-            let listener = UnixListener::bind(sock_file).context("binding on uds")?;
-            IpiListener::Unix(listener)
+            let addr = UnixSocketAddr::from_host(host);
+            debug!("listening on unix domain socket: {addr:?}");
+            let (listener, file) = addr.bind()?;
+            ListenerKind::Unix(listener, file)
         } else {
             debug!("listening on {host}:{port}");
             let listener = TcpListener::bind((host, port)).await.context("binding on inet")?;
-            IpiListener::Tcp(listener)
+            ListenerKind::Tcp(listener)
         };
 
-        Ok(x)
+        Ok(x.into())
     }
 }
 
 impl IpiListener {
+    /// Exchange messages with accepted drivers in binary layout specified
+    /// in `codec`.
+    pub fn with_codec(mut self, codec: codec::CodecOptions) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Set TCP keepalive on accepted drivers as in `keepalive`. For TLS
+    /// listener, it should be set in `Socket::bind_tls` instead.
+    pub fn with_keepalive(mut self, keepalive: KeepaliveOptions) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
     /// Accepts a new incoming connection from this listener.
     pub async fn accept(&self) -> Result<IpiStream> {
-        let s = match self {
-            Self::Tcp(l) => {
+        let mut s = match &self.kind {
+            ListenerKind::Tcp(l) => {
                 let (s, _) = l.accept().await?;
-                IpiStream::Tcp(s)
+                set_keepalive(&s, &self.keepalive)?;
+                IpiStream::from_stream(s)
             }
-            Self::Unix(l) => {
+            ListenerKind::Unix(l, _) => {
                 let (s, _) = l.accept().await?;
-                IpiStream::Unix(s)
+                IpiStream::from_stream(s)
             }
+            ListenerKind::Pipe(launcher) => launcher.launch().await?,
+            ListenerKind::Tls(l) => l.accept().await?,
         };
+        s.set_codec(self.codec);
         Ok(s)
     }
 }
 // ad23dfbd ends here
+
+// [[file:../ipi.note::f7a92c34][f7a92c34]]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// Max number of times to respawn drivers exited unexpectedly
+const MAX_RESPAWNS: usize = 10;
+/// The delay before respawning driver, to avoid hammering the system with
+/// a driver failing in startup
+const RESPAWN_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Spawn drivers which can not open sockets as child processes, and talk to
+/// them in i-PI protocol over their stdin/stdout pipes. Drivers exited
+/// unexpectedly will be respawned.
+#[derive(Debug)]
+pub struct PipeLauncher {
+    /// The shell command to start driver
+    command: String,
+    /// Max number of drivers running at the same time
+    copies: usize,
+    /// The number of running drivers
+    nrunning: Arc<AtomicUsize>,
+    /// The number of drivers spawned in total, including respawned ones
+    nspawned: AtomicUsize,
+    /// Notified when a driver exited
+    exited: Arc<Notify>,
+}
+
+impl PipeLauncher {
+    /// Spawn next driver, or wait until a running driver exited if all
+    /// copies are running. Return error if drivers have been respawned too
+    /// many times.
+    async fn launch(&self) -> Result<IpiStream> {
+        loop {
+            let n = self.nrunning.load(Ordering::SeqCst);
+            if n < self.copies {
+                if self.nrunning.compare_exchange(n, n + 1, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                    break;
+                }
+            } else {
+                self.exited.notified().await;
+            }
+        }
+
+        let nspawned = self.nspawned.fetch_add(1, Ordering::SeqCst);
+        if nspawned >= self.copies {
+            if nspawned >= self.copies + MAX_RESPAWNS {
+                self.nrunning.fetch_sub(1, Ordering::SeqCst);
+                bail!("driver exited too many times, give up respawning: {}", self.command);
+            }
+            warn!("respawn driver in {RESPAWN_DELAY:?}: {}", self.command);
+            tokio::time::sleep(RESPAWN_DELAY).await;
+        }
+
+        debug!("spawn driver: {}", self.command);
+        let child = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                self.nrunning.fetch_sub(1, Ordering::SeqCst);
+                return Err(err).with_context(|| format!("failed to spawn driver: {}", self.command));
+            }
+        };
+        let stdin = child.stdin.take().expect("driver stdin");
+        let stdout = child.stdout.take().expect("driver stdout");
+        // reap child process; it will be killed if dropped with runtime
+        let command = self.command.clone();
+        let nrunning = self.nrunning.clone();
+        let exited = self.exited.clone();
+        tokio::spawn(async move {
+            match child.wait().await {
+                Ok(status) if status.success() => debug!("driver exited: {command}"),
+                Ok(status) => error!("driver {command} exited with {status}"),
+                Err(err) => error!("failed to wait driver {command}: {err:?}"),
+            }
+            nrunning.fetch_sub(1, Ordering::SeqCst);
+            exited.notify_one();
+        });
+
+        Ok(IpiStream::new(stdout, stdin))
+    }
+}
+
+impl Socket {
+    /// Spawn `copies` of driver using shell `command`, talking i-PI protocol
+    /// over stdin/stdout.
+    pub fn spawn(command: &str, copies: usize) -> IpiListener {
+        ListenerKind::Pipe(PipeLauncher {
+            command: command.into(),
+            copies,
+            nrunning: Arc::new(AtomicUsize::new(0)),
+            nspawned: AtomicUsize::new(0),
+            exited: Arc::new(Notify::new()),
+        })
+        .into()
+    }
+}
+
+#[tokio::test]
+async fn test_pipe_launcher() -> Result<()> {
+    use std::time::Duration;
+
+    let listener = Socket::spawn("cat", 1);
+    let _stream = listener.accept().await?;
+    // no more drivers to spawn while the first one is running
+    let next = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+    assert!(next.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pipe_driver_respawn() -> Result<()> {
+    // the first driver crashes in computing, which is marked in a file
+    let marker = std::env::temp_dir().join(format!("gosh-ipi-pipe-crash-{}", std::process::id()));
+    let _ = std::fs::remove_file(&marker);
+    let command = format!("python3 tests/files/pipe-driver.py {}", marker.display());
+    let listener = Socket::spawn(&command, 1);
+    let (task_rx, task_tx) = task::Task::new().split();
+    let server = tokio::spawn(async move { listener.serve_channel(task_rx).await });
+
+    let mol = Molecule::from_file("tests/files/quinone.cif")?;
+    assert!(task_tx.remote_compute(mol.clone()).await.is_err());
+    assert!(marker.exists());
+    // the respawned driver takes over the following requests
+    for _ in 0..2 {
+        let computed = task_tx.remote_compute(mol.clone()).await?;
+        assert_eq!(computed.forces.len(), mol.natoms());
+        // -0.5 Hartree in eV
+        assert!((computed.energy + 0.5 * 27.211386).abs() < 1e-3);
+    }
+    drop(task_tx);
+    server.await??;
+    std::fs::remove_file(&marker)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_pipe_driver_give_up() -> Result<()> {
+    // a driver always failing in startup: respawned until giving up, and
+    // the server stops with error
+    let listener = Socket::spawn("exit 1", 1);
+    let launched = async {
+        loop {
+            listener.accept().await?;
+        }
+    };
+    let ret: Result<()> = tokio::time::timeout(RESPAWN_DELAY * (MAX_RESPAWNS as u32 + 5), launched).await?;
+    assert!(ret.is_err());
+
+    Ok(())
+}
+// f7a92c34 ends here
+
+// [[file:../ipi.note::5e7d0c81][5e7d0c81]]
+#[tokio::test]
+async fn test_tcp_keepalive() -> Result<()> {
+    let port = get_free_tcp_address().unwrap().port();
+    let listener = Socket::bind("localhost", port, false).await?;
+    let (server, client) = tokio::join!(listener.accept(), Socket::connect("localhost", port, false));
+    let (_server, _client) = (server?, client?);
+
+    let stream = TcpStream::connect(("localhost", port)).await?;
+    let options = KeepaliveOptions {
+        time: 30.0,
+        interval: 5.0,
+        retries: 3,
+    };
+    set_keepalive(&stream, &options)?;
+    let sock = socket2::SockRef::from(&stream);
+    assert!(sock.keepalive()?);
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    {
+        assert_eq!(sock.keepalive_time()?, std::time::Duration::from_secs(30));
+        assert_eq!(sock.keepalive_interval()?, std::time::Duration::from_secs(5));
+        assert_eq!(sock.keepalive_retries()?, 3);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unix_socket_cleanup() -> Result<()> {
+    let pid = std::process::id();
+
+    // explicit path, removed when listener dropped
+    let path = std::env::temp_dir().join(format!("gosh-ipi-test-{pid}.sock"));
+    let host = path.to_str().unwrap();
+    let listener = Socket::bind(host, 0, true).await?;
+    assert!(path.exists());
+    // refuse to remove socket file in use
+    assert!(Socket::bind(host, 0, true).await.is_err());
+    let _stream = Socket::connect(host, 0, true).await?;
+    drop(listener);
+    assert!(!path.exists());
+
+    // stale socket file left by dead process
+    drop(std::os::unix::net::UnixListener::bind(&path)?);
+    assert!(path.exists());
+    let listener = Socket::bind(host, 0, true).await?;
+    drop(listener);
+    assert!(!path.exists());
+
+    // Linux abstract namespace
+    #[cfg(target_os = "linux")]
+    {
+        let host = format!("@gosh-ipi-test-{pid}");
+        let listener = Socket::bind(&host, 0, true).await?;
+        let _stream = Socket::connect(&host, 0, true).await?;
+        listener.accept().await?;
+    }
+
+    Ok(())
+}
+// 5e7d0c81 ends here
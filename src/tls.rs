@@ -0,0 +1,251 @@
+// [[file:../ipi.note::3e8c1f27][3e8c1f27]]
+use super::*;
+use socket::{IpiListener, IpiStream, ListenerKind, Socket};
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+// 3e8c1f27 ends here
+
+// [[file:../ipi.note::a61d94b5][a61d94b5]]
+/// Certificates for TLS-encrypted i-PI connection between nodes. Both sides
+/// present their own certificate, which is verified against the CA
+/// certificate of the other side.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsOptions {
+    /// Path to certificate chain in PEM format
+    pub cert: PathBuf,
+    /// Path to private key in PEM format
+    pub key: PathBuf,
+    /// Path to CA certificates in PEM format for verifying the peer
+    pub ca: PathBuf,
+    /// The name for verifying server certificate on driver side. The host
+    /// name to connect will be used if not set.
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+fn load_certs(path: &Path) -> Result<Vec<rustls::Certificate>> {
+    let file = std::fs::File::open(path).with_context(|| format!("open certificate file {path:?}"))?;
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(file))?;
+    ensure!(!certs.is_empty(), "no certificate found in {path:?}");
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<rustls::PrivateKey> {
+    use rustls_pemfile::Item;
+
+    let file = std::fs::File::open(path).with_context(|| format!("open key file {path:?}"))?;
+    for item in rustls_pemfile::read_all(&mut std::io::BufReader::new(file))? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => return Ok(rustls::PrivateKey(key)),
+            _ => continue,
+        }
+    }
+    bail!("no private key found in {path:?}")
+}
+
+fn load_roots(path: &Path) -> Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(&cert).with_context(|| format!("invalid CA certificate in {path:?}"))?;
+    }
+    Ok(roots)
+}
+
+impl TlsOptions {
+    /// Create TLS acceptor for i-PI server, which requires drivers to
+    /// present certificate signed by CA.
+    fn acceptor(&self) -> Result<TlsAcceptor> {
+        let verifier = rustls::server::AllowAnyAuthenticatedClient::new(load_roots(&self.ca)?).boxed();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(load_certs(&self.cert)?, load_key(&self.key)?)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// Create TLS connector for i-PI driver, presenting its own certificate
+    /// to server.
+    fn connector(&self) -> Result<TlsConnector> {
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(&self.ca)?)
+            .with_client_auth_cert(load_certs(&self.cert)?, load_key(&self.key)?)?;
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+}
+// a61d94b5 ends here
+
+// [[file:../ipi.note::d07b4e93][d07b4e93]]
+/// Max time for a driver to finish TLS handshake
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Listener accepting TLS-encrypted connections from drivers. Handshakes
+/// are run concurrently in background, so a slow or stalled driver will not
+/// block others from connecting.
+pub struct TlsListener {
+    addr: std::net::SocketAddr,
+    accepted: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<Result<IpiStream>>>,
+    accept_loop: tokio::task::JoinHandle<()>,
+}
+
+impl std::fmt::Debug for TlsListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsListener").field("addr", &self.addr).finish()
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        self.accept_loop.abort();
+    }
+}
+
+/// Accept incoming connections on `listener`, and send streams completing
+/// TLS handshake into `tx`. Drivers failed in handshake (e.g. without
+/// valid certificate) are rejected without interrupting the server.
+async fn accept_loop(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    keepalive: socket::KeepaliveOptions,
+    tx: tokio::sync::mpsc::Sender<Result<IpiStream>>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                let _ = tx.send(Err(err.into())).await;
+                break;
+            }
+        };
+        if let Err(err) = socket::set_keepalive(&stream, &keepalive) {
+            warn!("rejected driver from {addr}: {err:?}");
+            continue;
+        }
+        let acceptor = acceptor.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = tx.send(Ok(IpiStream::from_stream(stream))).await;
+                }
+                Ok(Err(err)) => warn!("rejected driver from {addr}: {err}"),
+                Err(_) => warn!("rejected driver from {addr}: TLS handshake timed out"),
+            }
+        });
+    }
+}
+
+impl TlsListener {
+    /// Accept next driver completing TLS handshake.
+    pub(crate) async fn accept(&self) -> Result<IpiStream> {
+        self.accepted
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| format_err!("TLS listener on {} stopped", self.addr))?
+    }
+}
+
+impl Socket {
+    /// Listening on incoming TLS-encrypted connections on internet socket,
+    /// with TCP `keepalive` on accepted drivers.
+    pub async fn bind_tls(
+        host: &str,
+        port: u16,
+        tls: &TlsOptions,
+        keepalive: socket::KeepaliveOptions,
+    ) -> Result<IpiListener> {
+        let acceptor = tls.acceptor()?;
+        debug!("listening on {host}:{port} with TLS");
+        let listener = TcpListener::bind((host, port)).await.context("binding on inet")?;
+        let addr = listener.local_addr()?;
+        let (tx, rx) = tokio::sync::mpsc::channel(1);
+        let accept_loop = tokio::spawn(accept_loop(listener, acceptor, keepalive, tx));
+        let listener = TlsListener {
+            addr,
+            accepted: rx.into(),
+            accept_loop,
+        };
+        Ok(ListenerKind::Tls(listener).into())
+    }
+
+    /// Opens TLS-encrypted i-PI connection to server on internet socket.
+    pub async fn connect_tls(host: &str, port: u16, tls: &TlsOptions) -> Result<IpiStream> {
+        let connector = tls.connector()?;
+        let server_name = tls.server_name.as_deref().unwrap_or(host);
+        let server_name = rustls::ServerName::try_from(server_name).with_context(|| format!("invalid server name: {server_name}"))?;
+        debug!("connecting to socket {host}:{port} with TLS");
+        let stream = TcpStream::connect((host, port)).await.context("connect to inet")?;
+        socket::set_keepalive(&stream, &socket::KeepaliveOptions::default())?;
+        let stream = connector.connect(server_name, stream).await.context("TLS handshake")?;
+        Ok(IpiStream::from_stream(stream))
+    }
+}
+// d07b4e93 ends here
+
+// [[file:../ipi.note::6b2f58ca][6b2f58ca]]
+#[tokio::test]
+async fn test_tls_socket() -> Result<()> {
+    use std::time::Duration;
+
+    let options = |name: &str| TlsOptions {
+        cert: format!("tests/files/tls/{name}.pem").into(),
+        key: format!("tests/files/tls/{name}.key").into(),
+        ca: "tests/files/tls/ca.pem".into(),
+        server_name: None,
+    };
+    let port = socket::get_free_tcp_address().unwrap().port();
+    let listener = Socket::bind_tls("localhost", port, &options("server"), Default::default()).await?;
+
+    // drivers without valid client certificate are rejected: in TLS 1.3
+    // the client may complete its handshake first, and only see the alert
+    // from server in the following read
+    async fn rejected(connector: TlsConnector, port: u16) -> Result<std::io::Error> {
+        use tokio::io::AsyncReadExt;
+
+        let stream = TcpStream::connect(("localhost", port)).await?;
+        let server_name = rustls::ServerName::try_from("localhost")?;
+        let err = match connector.connect(server_name, stream).await {
+            Ok(mut stream) => match stream.read_u8().await {
+                Ok(_) => bail!("driver not rejected"),
+                Err(err) => err,
+            },
+            Err(err) => err,
+        };
+        Ok(err)
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_roots("tests/files/tls/ca.pem".as_ref())?)
+        .with_no_client_auth();
+    let (err, accepted) = tokio::select! {
+        err = rejected(TlsConnector::from(Arc::new(config)), port) => (err?, false),
+        _ = listener.accept() => (std::io::ErrorKind::Other.into(), true),
+    };
+    assert!(!accepted);
+    assert!(err.to_string().contains("CertificateRequired"), "{err}");
+    // server certificate is not for client authentication
+    let (err, accepted) = tokio::select! {
+        err = rejected(options("server").connector()?, port) => (err?, false),
+        _ = listener.accept() => (std::io::ErrorKind::Other.into(), true),
+    };
+    assert!(!accepted);
+    assert!(err.to_string().contains("alert"), "{err}");
+
+    // a stalled driver does not block others in handshake
+    let _stalled = TcpStream::connect(("localhost", port)).await?;
+
+    // mutual authentication
+    let connected = async { tokio::join!(listener.accept(), Socket::connect_tls("localhost", port, &options("client"))) };
+    let (server, client) = tokio::time::timeout(Duration::from_secs(2), connected).await?;
+    server?;
+    client?;
+
+    Ok(())
+}
+// 6b2f58ca ends here
@@ -0,0 +1,58 @@
+#! /usr/bin/env python3
+# Synthesize reference byte streams of a two-step i-PI session between
+# ASE's SocketIOCalculator (server side) and ASE's SocketClient (driver
+# side) for a water molecule in a 10 Å cubic box, following the message
+# order and framing of IPIProtocol in ase/calculators/socketio.py.
+#
+# NOTE: the streams are written by hand from reading ASE's source, NOT
+# captured from a running ASE. They check our codec against our reading of
+# ASE's conventions only, and should be replaced with a real capture.
+#
+# ase-server.dat: bytes sent by SocketIOCalculator to the driver
+# ase-client.dat: bytes sent by SocketClient back to the server
+import struct
+
+Bohr = 0.52917721067
+Ha = 27.21138602
+
+def msg(s):
+    return s.ljust(12).encode()
+
+cell = [10.0, 0.0, 0.0, 0.0, 10.0, 0.0, 0.0, 0.0, 10.0]
+icell = [0.1, 0.0, 0.0, 0.0, 0.1, 0.0, 0.0, 0.0, 0.1]
+steps = [
+    # positions, energy, forces
+    ([[5.0, 5.0, 5.1173], [5.0, 5.7572, 4.5296], [5.0, 4.2428, 4.5296]],
+     -14.2234, [[0.0, 0.0, -0.1232], [0.0, 0.2311, 0.0616], [0.0, -0.2311, 0.0616]]),
+    ([[5.0, 5.0, 5.1273], [5.0, 5.7572, 4.5296], [5.0, 4.2428, 4.5296]],
+     -14.2219, [[0.0, 0.0, -0.4105], [0.0, 0.2287, 0.2052], [0.0, -0.2287, 0.2053]]),
+]
+
+server = b""
+client = b""
+for positions, energy, forces in steps:
+    # calculate(): STATUS, NEEDINIT -> INIT, STATUS, READY
+    server += msg("STATUS")
+    client += msg("NEEDINIT")
+    server += msg("INIT") + struct.pack("<ii", 0, 1) + b"\0"
+    server += msg("STATUS")
+    client += msg("READY")
+    # sendposdata(): cell.T / Bohr, icell.T * Bohr
+    server += msg("POSDATA")
+    server += struct.pack("<9d", *[x / Bohr for x in cell])
+    server += struct.pack("<9d", *[x * Bohr for x in icell])
+    server += struct.pack("<i", len(positions))
+    server += struct.pack("<%dd" % (3 * len(positions)), *[x / Bohr for p in positions for x in p])
+    server += msg("STATUS")
+    client += msg("HAVEDATA")
+    # sendrecv_force() / sendforce(): one zero byte for extra data
+    server += msg("GETFORCE")
+    client += msg("FORCEREADY") + struct.pack("<d", energy / Ha)
+    client += struct.pack("<i", len(forces))
+    client += struct.pack("<%dd" % (3 * len(forces)), *[x * Bohr / Ha for f in forces for x in f])
+    client += struct.pack("<9d", *[0.0] * 9)
+    client += struct.pack("<i", 1) + b"\0"
+server += msg("EXIT")
+
+open("ase-server.dat", "wb").write(server)
+open("ase-client.dat", "wb").write(client)
@@ -0,0 +1,51 @@
+#! /usr/bin/env python3
+# A fake i-PI driver talking over stdin/stdout for tests, returning energy
+# of -0.5 Hartree and zero forces.
+#
+# If a marker file is given in command line and it does not exist yet, it
+# will be created, and the driver crashes on receiving POSDATA.
+import os
+import struct
+import sys
+
+inp = sys.stdin.buffer
+out = sys.stdout.buffer
+marker = sys.argv[1] if len(sys.argv) > 1 else None
+
+
+def read(n):
+    data = inp.read(n)
+    if len(data) < n:
+        sys.exit(0)
+    return data
+
+
+def send(data):
+    out.write(data)
+    out.flush()
+
+
+status = b"NEEDINIT"
+natoms = 0
+while True:
+    header = read(12).strip()
+    if header == b"STATUS":
+        send(status.ljust(12))
+    elif header == b"INIT":
+        _, n = struct.unpack("<ii", read(8))
+        read(n)
+        status = b"READY"
+    elif header == b"POSDATA":
+        read(144)
+        (natoms,) = struct.unpack("<i", read(4))
+        read(24 * natoms)
+        if marker and not os.path.exists(marker):
+            open(marker, "w").close()
+            sys.exit(1)
+        status = b"HAVEDATA"
+    elif header == b"GETFORCE":
+        forces = bytes(8 * (3 * natoms + 9))
+        send(b"FORCEREADY".ljust(12) + struct.pack("<di", -0.5, natoms) + forces + struct.pack("<i", 1) + b"\0")
+        status = b"READY"
+    elif header == b"EXIT":
+        break
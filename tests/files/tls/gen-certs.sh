@@ -0,0 +1,24 @@
+#!/bin/sh
+# Generate self-signed certificates for testing TLS transport: a CA, and
+# server/client certificates for localhost signed by the CA.
+set -e
+cd "$(dirname "$0")"
+
+openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+    -keyout ca.key -out ca.pem -days 36500 -subj "/CN=gosh-ipi test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign"
+
+for name in server client; do
+    openssl req -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes \
+        -keyout $name.key -out $name.csr -subj "/CN=gosh-ipi test $name"
+    cat > $name.ext <<EXT
+basicConstraints=critical,CA:FALSE
+keyUsage=critical,digitalSignature
+extendedKeyUsage=${name}Auth
+subjectAltName=DNS:localhost,IP:127.0.0.1
+EXT
+    openssl x509 -req -in $name.csr -CA ca.pem -CAkey ca.key -CAcreateserial \
+        -out $name.pem -days 36500 -extfile $name.ext
+    rm -f $name.csr $name.ext
+done
+rm -f ca.srl
@@ -0,0 +1,145 @@
+// [[file:../ipi.note::c4e82b19][c4e82b19]]
+//! Drive the proxy server and client through the public API, as a host
+//! application or workflow engine would do.
+
+use gosh_core::gchemol::Molecule;
+use gosh_core::gut::prelude::*;
+use gosh_ipi::composite::DriverConfig;
+use gosh_ipi::rest::{Client, RetryOptions, Server, ServerInfo, ServerOptions};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// A driver speaking i-PI protocol on unix socket in `path`, returning
+/// energy of -0.5 Hartree and zero forces.
+async fn fake_driver(path: String) -> std::io::Result<()> {
+    let mut stream = loop {
+        match UnixStream::connect(&path).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(50)).await,
+        }
+    };
+    let mut status = "NEEDINIT";
+    let mut natoms = 0;
+    loop {
+        let mut header = [0u8; 12];
+        if stream.read_exact(&mut header).await.is_err() {
+            break;
+        }
+        match std::str::from_utf8(&header).unwrap().trim() {
+            "STATUS" => stream.write_all(format!("{status:12}").as_bytes()).await?,
+            "INIT" => {
+                let _ibead = stream.read_i32_le().await?;
+                let n = stream.read_i32_le().await?;
+                stream.read_exact(&mut vec![0; n as usize]).await?;
+                status = "READY";
+            }
+            "POSDATA" => {
+                stream.read_exact(&mut [0; 144]).await?;
+                natoms = stream.read_i32_le().await? as usize;
+                stream.read_exact(&mut vec![0; 24 * natoms]).await?;
+                status = "HAVEDATA";
+            }
+            "GETFORCE" => {
+                let mut msg = b"FORCEREADY  ".to_vec();
+                msg.extend((-0.5f64).to_le_bytes());
+                msg.extend((natoms as i32).to_le_bytes());
+                msg.extend(vec![0; 8 * (3 * natoms + 9)]);
+                msg.extend(1i32.to_le_bytes());
+                msg.push(0);
+                stream.write_all(&msg).await?;
+                status = "NEEDINIT";
+            }
+            "EXIT" => break,
+            header => panic!("unexpected message: {header}"),
+        }
+    }
+    Ok(())
+}
+
+/// Compute `mols` using `client` in JSON and binary payloads, and in a
+/// stream session, checking that results are returned with the submitted
+/// molecules.
+async fn compute_all(client: Client, mols: &[Molecule]) -> Result<()> {
+    let check_energy = |energy: f64| assert!((energy + 0.5 * 27.211386).abs() < 1e-3, "{energy}");
+
+    let mps = client.compute_molecules_async(mols).await?;
+    assert_eq!(mps.len(), mols.len());
+    for (mp, mol) in mps.iter().zip(mols) {
+        check_energy(mp.get_energy().unwrap());
+        assert_eq!(mp.get_forces().unwrap().len(), mol.natoms());
+        let submitted = mp.get_molecule().unwrap();
+        assert_eq!(submitted.title(), mol.title());
+        assert!(submitted.positions().eq(mol.positions()));
+    }
+
+    // virial and extra data are kept in binary payloads too
+    let client = client.with_binary(true);
+    for mol in mols {
+        let computed = client.compute_results_async(mol).await?;
+        check_energy(computed.energy());
+        assert_eq!(computed.virial(), [0.0; 9]);
+        assert!(computed.extra().is_empty());
+        let mp = client.compute_molecule_async(mol).await?;
+        assert_eq!(mp.get_molecule().unwrap().title(), mol.title());
+    }
+
+    let mut session = client.stream().await?;
+    for mol in mols {
+        let mp = session.compute(mol).await?;
+        check_energy(mp.get_energy().unwrap());
+        assert_eq!(mp.get_molecule().unwrap().title(), mol.title());
+        let computed = session.compute_results(mol).await?;
+        check_energy(computed.energy());
+        assert_eq!(computed.virial(), [0.0; 9]);
+    }
+    session.close().await?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rest_api() -> Result<()> {
+    let pid = std::process::id();
+    let tmp = std::env::temp_dir();
+    let lock_file = tmp.join(format!("gosh-ipi-api-{pid}.lock"));
+    let ipi = DriverConfig {
+        host: format!("gosh-ipi-api-{pid}"),
+        unix: true,
+        ..Default::default()
+    };
+    let options = ServerOptions {
+        ipi,
+        rest_socket: Some(tmp.join(format!("gosh-ipi-api-{pid}.sock"))),
+        ..Default::default()
+    };
+    let driver = tokio::spawn(fake_driver(format!("/tmp/ipi_gosh-ipi-api-{pid}")));
+
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = Server::serve_with_shutdown(&lock_file, options, async {
+        let _ = stopped.await;
+    });
+    let client = async {
+        let path = lock_file.clone();
+        let info = tokio::task::spawn_blocking(move || ServerInfo::from_lock_file(&path, 5.0)).await??;
+        let retry = RetryOptions {
+            max_retries: 1,
+            ..Default::default()
+        };
+        let client = Client::from_server_info(&info).with_retry(retry);
+        let mut mol = Molecule::from_file("tests/files/quinone.cif")?;
+        let mut other = mol.clone();
+        mol.set_title("first");
+        other.set_title("second");
+        let computed = compute_all(client, &[mol.clone(), other.clone()]).await;
+        let _ = stop.send(());
+        computed
+    };
+    let (served, computed) = tokio::join!(server, client);
+    computed?;
+    served?;
+    driver.await??;
+
+    Ok(())
+}
+// c4e82b19 ends here